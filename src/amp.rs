@@ -0,0 +1,444 @@
+use regex::Regex;
+use url::Url;
+
+use crate::error::Error;
+use crate::html::{find_canonical, is_amp_document};
+#[cfg(test)]
+use crate::resolver::test_resolver;
+use crate::resolver::{get_resolver, RedirectResolver};
+#[cfg(test)]
+use crate::test_server::{TestResponse, TestServer};
+use crate::utils_api::{CanonicalInfo, Item, OriginInfo};
+
+/// Google and the AMP cache can wrap each other and the page itself can have markers, so this is done a few times.
+const MAX_UNWRAP_ROUNDS: usize = 3;
+
+/// Canonical links with paths less similar than this to the AMP url are ignored, they might point anywhere.
+const MIN_URL_SIMILARITY: f64 = 0.5;
+
+static AMP_CACHE_PATH_REGEX: once_cell::sync::OnceCell<Regex> = once_cell::sync::OnceCell::new();
+static AMP_HINT_REGEX: once_cell::sync::OnceCell<Regex> = once_cell::sync::OnceCell::new();
+
+#[derive(Debug, PartialEq)]
+pub enum AmpUrl {
+    /// Nothing about the url points to AMP.
+    NotAmp,
+    /// The url had one of the well known AMP shapes, which was removed offline.
+    Unwrapped(Url),
+    /// The url mentions AMP somewhere, but not in a shape we can remove without looking at the page.
+    Ambiguous,
+}
+
+/// Rewrites the well known AMP url shapes to their canonical urls, without any network requests:
+/// `google.com/amp/s/...`, `*.cdn.ampproject.org/c/s/...`, a trailing `/amp` path segment, `?amp=1` and `outputType=amp`.
+pub fn unwrap_amp(url: &Url) -> AmpUrl {
+    let mut current_url = url.clone();
+
+    for _ in 0..MAX_UNWRAP_ROUNDS {
+        match unwrap_amp_viewer(&current_url).or_else(|| remove_amp_markers(&current_url)) {
+            Some(unwrapped_url) => current_url = unwrapped_url,
+            None => break,
+        }
+    }
+
+    if current_url != *url {
+        AmpUrl::Unwrapped(current_url)
+    } else if looks_like_amp(url) {
+        AmpUrl::Ambiguous
+    } else {
+        AmpUrl::NotAmp
+    }
+}
+
+/// Extracts the original url from the Google AMP viewer and the AMP cache.
+/// The `s/` part tells that the original url uses https.
+fn unwrap_amp_viewer(url: &Url) -> Option<Url> {
+    let host = url.host_str()?.to_lowercase();
+    let path = url.path();
+
+    let (is_https, wrapped_url) = if is_google_domain(host.as_str()) {
+        if let Some(wrapped_url) = path.strip_prefix("/amp/s/") {
+            (true, wrapped_url)
+        } else {
+            (false, path.strip_prefix("/amp/")?)
+        }
+    } else if host.ends_with(".cdn.ampproject.org") {
+        let amp_cache_path_regex = AMP_CACHE_PATH_REGEX.get_or_init(|| {
+            Regex::new(r"^/(?:c|v)/(s/)?(.+)$").expect("Cannot build AMP cache path regex.")
+        });
+        let captures = amp_cache_path_regex.captures(path)?;
+        (captures.get(1).is_some(), captures.get(2)?.as_str())
+    } else {
+        return None;
+    };
+
+    let scheme = if is_https { "https" } else { "http" };
+    let unwrapped_url = match url.query() {
+        Some(query) => format!("{}://{}?{}", scheme, wrapped_url, query),
+        None => format!("{}://{}", scheme, wrapped_url),
+    };
+
+    Url::parse(unwrapped_url.as_str())
+        .ok()
+        .filter(|unwrapped_url| {
+            unwrapped_url
+                .host_str()
+                .is_some_and(|host| host.contains('.'))
+        })
+}
+
+/// Matches `google.com`, `www.google.de`, `google.co.uk` and so on.
+fn is_google_domain(host: &str) -> bool {
+    host.strip_prefix("www.")
+        .unwrap_or(host)
+        .strip_prefix("google.")
+        .is_some_and(|suffix| !suffix.is_empty() && !suffix.contains("google"))
+}
+
+/// Removes a trailing `/amp` path segment as well as `amp=1` and `outputType=amp` query parameters.
+/// `amp` segments anywhere else in the path can be part of the page name, so they are left alone.
+fn remove_amp_markers(url: &Url) -> Option<Url> {
+    let mut cleaned_url = url.clone();
+    let mut did_changes = false;
+
+    let path = url.path();
+    let kept_path = match path.strip_suffix("/amp/") {
+        Some(kept_path) => Some(format!("{}/", kept_path)),
+        None => path
+            .strip_suffix("/amp")
+            .map(|kept_path| kept_path.to_string()),
+    };
+    if let Some(kept_path) = kept_path {
+        cleaned_url.set_path(kept_path.as_str());
+        did_changes = true;
+    }
+
+    if let Some(query) = url.query() {
+        let kept_parameters: Vec<&str> = query
+            .split('&')
+            .filter(|parameter| !is_amp_parameter(parameter))
+            .collect();
+        if kept_parameters.len() != query.split('&').count() {
+            let query = kept_parameters.join("&");
+            cleaned_url.set_query(Some(query.as_str()).filter(|query| !query.is_empty()));
+            did_changes = true;
+        }
+    }
+
+    did_changes.then_some(cleaned_url)
+}
+
+fn is_amp_parameter(parameter: &str) -> bool {
+    let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+
+    (key.eq_ignore_ascii_case("amp") && matches!(value.to_lowercase().as_str(), "" | "1" | "true"))
+        || (key.eq_ignore_ascii_case("outputtype") && value.eq_ignore_ascii_case("amp"))
+}
+
+/// Hosts like `amp.example.com` or paths like `/article.amp.html` are AMP as well, but there's no
+/// general rule to get to the canonical page from them.
+fn looks_like_amp(url: &Url) -> bool {
+    let amp_hint_regex = AMP_HINT_REGEX.get_or_init(|| {
+        Regex::new(r"(?:^|[/._&?-])amp(?:html)?(?:$|[/._=&-])")
+            .expect("Cannot build AMP hint regex.")
+    });
+
+    let is_amp_host = url.host_str().is_some_and(|host| {
+        host.split('.')
+            .any(|label| label.eq_ignore_ascii_case("amp"))
+    });
+    let path_and_query = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+
+    is_amp_host || amp_hint_regex.is_match(path_and_query.as_str())
+}
+
+/// Looks up the canonical url of an AMP page by downloading it and reading its `rel=canonical` link.
+/// The result has the format of the amputatorbot api, which was used for this before.
+pub async fn check_for_amp(url: &str) -> Result<Vec<Item>, Error> {
+    lookup_amp_page(get_resolver(), &Url::parse(url)?).await
+}
+
+async fn lookup_amp_page(resolver: &RedirectResolver, url: &Url) -> Result<Vec<Item>, Error> {
+    let Some(html) = resolver.fetch_page(url).await? else {
+        return Ok(vec![]);
+    };
+
+    let canonical = find_canonical(html.as_str())
+        .and_then(|canonical_url| url.join(canonical_url.as_str()).ok())
+        .map(|canonical_url| {
+            let url_similarity = url_similarity(url, &canonical_url);
+
+            CanonicalInfo {
+                domain: canonical_url.host_str().unwrap_or_default().to_string(),
+                is_alt: false,
+                is_amp: unwrap_amp(&canonical_url) != AmpUrl::NotAmp,
+                is_cached: Some(false),
+                is_valid: is_trusted_canonical(url, &canonical_url),
+                type_: "REL".to_string(),
+                url: canonical_url.to_string(),
+                url_similarity,
+            }
+        });
+
+    Ok(vec![Item {
+        amp_canonical: None,
+        canonical,
+        origin: OriginInfo {
+            domain: url.host_str().unwrap_or_default().to_string(),
+            is_amp: is_amp_document(html.as_str()),
+            is_cached: false,
+            is_valid: true,
+            url: url.to_string(),
+        },
+    }])
+}
+
+/// A canonical link on another site or to an unrelated page, e.g. the home page, isn't trusted.
+pub fn is_trusted_canonical(url: &Url, canonical_url: &Url) -> bool {
+    is_same_site(url, canonical_url) && url_similarity(url, canonical_url) >= MIN_URL_SIMILARITY
+}
+
+/// Compares the hosts without a leading `amp.` or `www.`, e.g. `amp.zdf.de` and `www.zdf.de` are the same site.
+/// Other subdomains count as another site, as there's no public suffix list to tell them apart from domains.
+fn is_same_site(url: &Url, other_url: &Url) -> bool {
+    match (url.host_str(), other_url.host_str()) {
+        (Some(host), Some(other_host)) => site_host(host) == site_host(other_host),
+        _ => false,
+    }
+}
+
+fn site_host(host: &str) -> String {
+    let host = host.trim_end_matches('.').to_lowercase();
+
+    match host
+        .strip_prefix("amp.")
+        .or_else(|| host.strip_prefix("www."))
+    {
+        Some(site_host) => site_host.to_string(),
+        None => host,
+    }
+}
+
+/// Dice coefficient of the character pairs in both paths, after removing the AMP markers from the AMP url.
+fn url_similarity(amp_url: &Url, canonical_url: &Url) -> f64 {
+    let amp_url = remove_amp_markers(amp_url).unwrap_or_else(|| amp_url.clone());
+    let amp_path = amp_url.path().trim_end_matches('/').to_lowercase();
+    let canonical_path = canonical_url.path().trim_end_matches('/').to_lowercase();
+
+    if amp_path == canonical_path {
+        return 1.0;
+    }
+
+    let amp_pairs = character_pairs(amp_path.as_str());
+    let mut canonical_pairs = character_pairs(canonical_path.as_str());
+    let pair_count = amp_pairs.len() + canonical_pairs.len();
+
+    let shared_pairs = amp_pairs
+        .iter()
+        .filter(|pair| {
+            let position = canonical_pairs
+                .iter()
+                .position(|other_pair| other_pair == *pair);
+            position
+                .map(|position| canonical_pairs.swap_remove(position))
+                .is_some()
+        })
+        .count();
+
+    if pair_count == 0 {
+        0.0
+    } else {
+        2.0 * shared_pairs as f64 / pair_count as f64
+    }
+}
+
+fn character_pairs(text: &str) -> Vec<(char, char)> {
+    let characters: Vec<char> = text.chars().collect();
+    characters
+        .windows(2)
+        .map(|pair| (pair[0], pair[1]))
+        .collect()
+}
+
+#[test]
+fn test_unwrap_amp() {
+    let unwrapped_cases = [
+        (
+            "https://www.google.com/amp/s/electrek.co/2018/06/19/tesla-model-3-assembly-line-inside-tent-elon-musk/amp/",
+            "https://electrek.co/2018/06/19/tesla-model-3-assembly-line-inside-tent-elon-musk/",
+        ),
+        (
+            "https://www.google.de/amp/www.example.com/artikel?id=5",
+            "http://www.example.com/artikel?id=5",
+        ),
+        (
+            "https://www-example-com.cdn.ampproject.org/c/s/www.example.com/news/story-1/amp",
+            "https://www.example.com/news/story-1",
+        ),
+        (
+            "https://example-com.cdn.ampproject.org/v/example.com/article",
+            "http://example.com/article",
+        ),
+        (
+            "https://www.example.com/2023/05/article/amp",
+            "https://www.example.com/2023/05/article",
+        ),
+        (
+            "https://www.example.com/article?amp=1&page=2",
+            "https://www.example.com/article?page=2",
+        ),
+        (
+            "https://www.example.com/article?amp",
+            "https://www.example.com/article",
+        ),
+        (
+            "https://www.example.com/article.html?outputType=amp",
+            "https://www.example.com/article.html",
+        ),
+    ];
+
+    for (url, expected) in unwrapped_cases {
+        assert_eq!(
+            unwrap_amp(&Url::parse(url).unwrap()),
+            AmpUrl::Unwrapped(Url::parse(expected).unwrap()),
+            "Url: {}",
+            url
+        );
+    }
+
+    let not_amp_cases = [
+        "https://www.example.com/article?page=2",
+        "https://www.example.com/camp/example",
+        "https://www.google.com/search?q=amp",
+        "https://en.wikipedia.org/wiki/AMP",
+    ];
+    for url in not_amp_cases {
+        assert_eq!(
+            unwrap_amp(&Url::parse(url).unwrap()),
+            AmpUrl::NotAmp,
+            "Url: {}",
+            url
+        );
+    }
+
+    let ambiguous_cases = [
+        "https://amp.example.com/article",
+        "https://www.example.com/article.amp.html",
+        "https://www.example.com/amp-stories/story",
+        "https://www.example.com/article?amp=false",
+        "https://www.example.com/amp/article",
+    ];
+    for url in ambiguous_cases {
+        assert_eq!(
+            unwrap_amp(&Url::parse(url).unwrap()),
+            AmpUrl::Ambiguous,
+            "Url: {}",
+            url
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_lookup_amp_page() {
+    let server = TestServer::start(std::collections::HashMap::from([
+        (
+            "/news/amp.article-123".to_string(),
+            TestResponse::ok(r#"<html amp><head><link rel="canonical" href="/news/article-123"></head></html>"#),
+        ),
+        (
+            "/news/amp.spoofed".to_string(),
+            TestResponse::ok(r#"<html amp><head><link rel="canonical" href="https://bank.example.org/news/spoofed"></head></html>"#),
+        ),
+        (
+            "/news/amp.home".to_string(),
+            TestResponse::ok(r#"<html amp><head><link rel="canonical" href="/"></head></html>"#),
+        ),
+        (
+            "/news/not-amp".to_string(),
+            TestResponse::ok(r#"<html><head><link rel="canonical" href="/news/not-amp"></head></html>"#),
+        ),
+    ]))
+    .await;
+    let resolver = test_resolver(5);
+    let lookup = |path: &str| {
+        let url = Url::parse(server.url(path).as_str()).unwrap();
+        let resolver = &resolver;
+        async move {
+            lookup_amp_page(resolver, &url)
+                .await
+                .expect("Unable to look up page.")
+        }
+    };
+
+    let items = lookup("/news/amp.article-123").await;
+    assert!(items[0].origin.is_amp);
+    let canonical = items[0].canonical.as_ref().unwrap();
+    assert_eq!(canonical.url, server.url("/news/article-123"));
+    assert!(canonical.is_valid);
+    assert!(!canonical.is_amp);
+
+    let items = lookup("/news/amp.spoofed").await;
+    assert!(!items[0].canonical.as_ref().unwrap().is_valid);
+
+    let items = lookup("/news/amp.home").await;
+    assert!(!items[0].canonical.as_ref().unwrap().is_valid);
+
+    let items = lookup("/news/not-amp").await;
+    assert!(!items[0].origin.is_amp);
+
+    assert!(lookup("/missing").await.is_empty());
+}
+
+#[test]
+fn test_url_similarity() {
+    let url = |url: &str| Url::parse(url).unwrap();
+
+    assert_eq!(
+        url_similarity(
+            &url("https://www.example.com/news/story-1/amp"),
+            &url("https://www.example.com/news/story-1/")
+        ),
+        1.0
+    );
+    assert!(
+        url_similarity(
+            &url("https://amp.example.com/politik/article.amp.html"),
+            &url("https://www.example.com/politik/article.html")
+        ) >= MIN_URL_SIMILARITY
+    );
+    assert!(
+        url_similarity(
+            &url("https://amp.example.com/politik/article.amp.html"),
+            &url("https://www.example.com/")
+        ) < MIN_URL_SIMILARITY
+    );
+}
+
+#[test]
+fn test_is_same_site() {
+    let url = |url: &str| Url::parse(url).unwrap();
+
+    assert!(is_same_site(
+        &url("https://amp.zdf.de/nachrichten/politik/artikel-100.html"),
+        &url("https://www.zdf.de/nachrichten/politik/artikel-100.html")
+    ));
+    assert!(is_same_site(
+        &url("https://amp.zdf.de/nachrichten/artikel-100.html"),
+        &url("https://zdf.de/nachrichten/artikel-100.html")
+    ));
+    assert!(is_same_site(
+        &url("https://amp.bbc.co.uk/news/article"),
+        &url("https://www.bbc.co.uk/news/article")
+    ));
+    assert!(!is_same_site(
+        &url("https://amp.example.co.uk/news/article"),
+        &url("https://bank.example.co.uk/news/article")
+    ));
+    assert!(!is_same_site(
+        &url("https://amp.zdf.de/nachrichten/artikel-100.html"),
+        &url("https://zdf.de.example.org/nachrichten/artikel-100.html")
+    ));
+}
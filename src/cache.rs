@@ -0,0 +1,113 @@
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::get_config;
+use crate::error::Error;
+#[cfg(test)]
+use crate::store::test_file_path;
+use crate::store::{load_json, save_json, TtlMap};
+
+static URL_CACHE: once_cell::sync::OnceCell<UrlCache> = once_cell::sync::OnceCell::new();
+
+/// Results of the network lookups for urls that were already looked at, so popular links aren't requested every time.
+/// Only the AMP and redirect results are stored, cleaning itself depends on the current rules and config.
+/// It's stored on disk and survives restarts.
+pub struct UrlCache {
+    path: String,
+    ttl: Duration,
+    content: Mutex<UrlCacheContent>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct UrlCacheContent {
+    /// Canonical url of an AMP page, `None` if the url isn't an AMP page
+    amp_urls: TtlMap<String, Option<String>>,
+    /// Final url after all redirects, `None` if the url doesn't redirect
+    redirect_urls: TtlMap<String, Option<String>>,
+    /// Set if something changed since the cache was last written
+    #[serde(skip)]
+    dirty: bool,
+}
+
+pub fn get_url_cache() -> &'static UrlCache {
+    URL_CACHE.get_or_init(|| {
+        let config = get_config();
+        UrlCache::open(config.url_cache_path.as_str(), config.url_cache_ttl)
+    })
+}
+
+impl UrlCache {
+    pub fn open(path: &str, ttl: Duration) -> UrlCache {
+        UrlCache {
+            path: path.to_string(),
+            ttl,
+            content: Mutex::new(load_json(path)),
+        }
+    }
+
+    pub fn get_amp(&self, url: &str) -> Option<Option<String>> {
+        self.content().amp_urls.get(&url.to_string()).cloned()
+    }
+
+    pub fn set_amp(&self, url: &str, canonical_url: Option<String>) {
+        let mut content = self.content();
+        content
+            .amp_urls
+            .insert(url.to_string(), canonical_url, self.ttl);
+        content.dirty = true;
+    }
+
+    pub fn get_redirect(&self, url: &str) -> Option<Option<String>> {
+        self.content().redirect_urls.get(&url.to_string()).cloned()
+    }
+
+    pub fn set_redirect(&self, url: &str, result_url: Option<String>) {
+        let mut content = self.content();
+        content
+            .redirect_urls
+            .insert(url.to_string(), result_url, self.ttl);
+        content.dirty = true;
+    }
+
+    /// Drops expired entries and writes the cache to disk, if anything changed since the last time.
+    pub fn save(&self) -> Result<(), Error> {
+        let mut content = self.content();
+        if !content.dirty {
+            return Ok(());
+        }
+
+        content.amp_urls.remove_expired();
+        content.redirect_urls.remove_expired();
+
+        save_json(self.path.as_str(), &*content)?;
+        content.dirty = false;
+
+        Ok(())
+    }
+
+    fn content(&self) -> std::sync::MutexGuard<'_, UrlCacheContent> {
+        self.content.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[test]
+fn test_url_cache_is_only_saved_after_changes() {
+    let path = test_file_path("url_cache.json");
+    let url_cache = UrlCache::open(path.as_str(), Duration::from_secs(60));
+
+    url_cache.save().expect("Unable to save url cache.");
+    assert!(!std::path::Path::new(path.as_str()).exists());
+
+    url_cache.set_redirect(
+        "https://bit.ly/abc",
+        Some("https://example.com/".to_string()),
+    );
+    url_cache.save().expect("Unable to save url cache.");
+    assert!(std::path::Path::new(path.as_str()).exists());
+
+    std::fs::remove_file(path.as_str()).expect("Unable to remove url cache.");
+    url_cache.save().expect("Unable to save url cache.");
+    assert!(!std::path::Path::new(path.as_str()).exists());
+}
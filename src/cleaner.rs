@@ -1,20 +1,83 @@
 use std::time::Duration;
 
 use regex::{Regex, RegexBuilder};
-use url::Url;
+use url::{form_urlencoded, Url};
 
+use crate::amp::{check_for_amp, unwrap_amp, AmpUrl};
+use crate::cache::{get_url_cache, UrlCache};
 use crate::error::Error;
-use crate::pro_api::{get_latest_messages, get_post, has_unread_messages, reply_comment, Message};
-use crate::providers::{compile_providers, CompiledProviderDetails};
-use crate::utils_api::{check_for_amp, get_redirects};
+use crate::extractor::extract_urls;
+#[cfg(test)]
+use crate::fake_pr0gramm::{comment, mention, FakePr0gramm};
+use crate::handled::{get_handled_messages, HandledMessages};
+use crate::pro_api::{get_pro_client, Message, ProClient};
+use crate::providers::{get_providers, CompiledProviders};
+#[cfg(test)]
+use crate::providers::{test_provider, test_providers};
+use crate::resolver::{get_resolver, is_shortener};
+#[cfg(test)]
+use crate::store::test_file_path;
+#[cfg(test)]
+use crate::test_server::TestResponse;
+use crate::utils_api::Item;
+
+#[derive(Debug, PartialEq)]
+pub struct CleanedUrl {
+    pub url: String,
+    /// Set if a referral marketing parameter (e.g. an affiliate tag) was removed from the url.
+    pub removed_referral_marketing: bool,
+    /// Set if the whole domain is a tracking or ad domain without a clean equivalent.
+    /// The url is left as it is in that case.
+    pub blocked: bool,
+}
+
+impl CleanedUrl {
+    fn new(url: &Url, removed_referral_marketing: bool) -> CleanedUrl {
+        CleanedUrl {
+            url: url.to_string().trim_end_matches('?').to_string(),
+            removed_referral_marketing,
+            blocked: false,
+        }
+    }
+
+    fn blocked(url: &str) -> CleanedUrl {
+        CleanedUrl {
+            url: url.to_string(),
+            removed_referral_marketing: false,
+            blocked: true,
+        }
+    }
+}
+
+const MAX_PROVIDER_REDIRECTIONS: usize = 5;
 
-static PROVIDER: async_once_cell::OnceCell<Vec<CompiledProviderDetails>> =
-    async_once_cell::OnceCell::new();
-static URL_REGEX: once_cell::sync::OnceCell<Regex> = once_cell::sync::OnceCell::new();
 static CLIENT_REGEX: once_cell::sync::OnceCell<Regex> = once_cell::sync::OnceCell::new();
 
 pub async fn run_linkers() -> Result<(), Error> {
-    let providers = PROVIDER.get_or_init(compile_providers()).await;
+    let providers = get_providers().await;
+    let url_cache = get_url_cache();
+    let run_result = answer_mentions(
+        get_pro_client(),
+        &providers,
+        get_handled_messages(),
+        url_cache,
+    )
+    .await;
+
+    if let Err(error) = url_cache.save() {
+        println!("Unable to save url cache. Error: {}", error);
+    }
+
+    run_result
+}
+
+/// Answers all new mentions in the inbox with the cleaned links of the comment the mention replies to.
+async fn answer_mentions(
+    pro_client: &ProClient,
+    providers: &CompiledProviders,
+    handled_messages: &HandledMessages,
+    url_cache: &UrlCache,
+) -> Result<(), Error> {
     let bot_name_regex = CLIENT_REGEX.get_or_init(|| {
         RegexBuilder::new(r"(@linkers)")
             .case_insensitive(true)
@@ -22,43 +85,60 @@ pub async fn run_linkers() -> Result<(), Error> {
             .expect("Cannot build bot name regex.")
     });
 
-    if !has_unread_messages().await? {
-        return Ok(());
-    }
+    // Failed mentions from earlier runs are retried first, pr0gramm doesn't list them as unread anymore
+    let mut mentions = handled_messages.pending();
 
-    let message_collection = get_latest_messages().await?;
+    if pro_client.has_unread_messages().await? {
+        let message_collection = pro_client.get_latest_messages().await?;
 
-    let new_comments: Vec<&Message> = message_collection
-        .messages
-        .iter()
-        .filter(|x1| x1.read == 0)
-        .filter(|x1| x1.message_type.eq("comment"))
-        .filter(|x| bot_name_regex.is_match(x.message.as_str()))
-        .filter(|x| x.item_id.is_some())
-        .collect();
+        let new_comments: Vec<&Message> = message_collection
+            .messages
+            .iter()
+            .filter(|x1| x1.read == 0)
+            .filter(|x1| !handled_messages.contains(x1.id))
+            .filter(|x1| x1.message_type.eq("comment"))
+            .filter(|x| bot_name_regex.is_match(x.message.as_str()))
+            .filter(|x| x.item_id.is_some())
+            .collect();
 
-    println!("{:?}", new_comments);
+        println!("{:?}", new_comments);
 
-    for tag_comment in new_comments {
-        let Some(item_id) = tag_comment.item_id else {
-            continue;
-        };
+        mentions.extend(
+            new_comments
+                .iter()
+                .filter_map(|tag_comment| Some((tag_comment.id, tag_comment.item_id?))),
+        );
+    }
 
+    for (mention_id, item_id) in mentions {
         tokio::time::sleep(Duration::from_secs(1)).await; //Prevent spamming the API in one go and give it some breathing room
 
-        let post = get_post(item_id).await?;
+        let post = match pro_client.get_post(item_id).await {
+            Ok(post) => post,
+            Err(error) => {
+                println!(
+                    "Unable to load post {} of mention {}. Error: {}",
+                    item_id, mention_id, error
+                );
+                mark_failed(handled_messages, mention_id, item_id);
+                continue;
+            }
+        };
         let optional_post_comment = post
             .comments
             .iter()
-            .find(|comment| comment.id == tag_comment.id);
+            .find(|comment| comment.id == mention_id);
 
         println!("post Comment: {:?}", optional_post_comment);
 
+        // Mentions which will never get an answer are handled as well, so their post isn't fetched again
         let Some(post_comment) = optional_post_comment else {
+            mark_handled(handled_messages, mention_id);
             continue;
         };
 
         if post_comment.parent == 0 {
+            mark_handled(handled_messages, mention_id);
             continue;
         }
 
@@ -70,31 +150,58 @@ pub async fn run_linkers() -> Result<(), Error> {
         println!("parent Comment: {:?}", optional_parent_comment);
 
         let Some(parent_comment) = optional_parent_comment else {
+            mark_handled(handled_messages, mention_id);
             continue;
         };
 
-        let links = cleanup_comment(&parent_comment.content, providers).await;
+        let links = cleanup_comment(&parent_comment.content, providers, url_cache).await;
 
         println!("answer {:?}", links);
 
-        reply_comment(item_id, post_comment.id, build_response_text(links)).await;
+        // Mentions whose answer was rejected stay pending, so the next run tries again.
+        // Other errors might happen after pr0gramm got the comment, retrying them could answer twice.
+        let reply_result = pro_client
+            .reply_comment(item_id, post_comment.id, build_response_text(links))
+            .await;
+        match reply_result {
+            Ok(()) => mark_handled(handled_messages, mention_id),
+            Err(error) if is_rejected_reply(&error) => {
+                println!(
+                    "Unable to answer mention {} on post {}. Error: {}",
+                    mention_id, item_id, error
+                );
+                mark_failed(handled_messages, mention_id, item_id);
+            }
+            Err(error) => {
+                println!(
+                    "Answer to mention {} on post {} might be posted, it isn't tried again. Error: {}",
+                    mention_id, item_id, error
+                );
+                mark_handled(handled_messages, mention_id);
+            }
+        }
     }
 
     Ok(())
 }
 
-async fn cleanup_comment(input: &str, providers: &[CompiledProviderDetails]) -> Vec<String> {
+async fn cleanup_comment(
+    input: &str,
+    providers: &CompiledProviders,
+    url_cache: &UrlCache,
+) -> Vec<CleanedUrl> {
     let mut output = Vec::new();
-    let urls_regex = URL_REGEX.get_or_init(|| {
-        Regex::new(r"(https?://\S+)").expect("Cannot build url regex. Bot won't work.")
-    });
 
-    for url in urls_regex.find_iter(input) {
-        let Some(cleaner_url) = clean_url(url.as_str(), providers).await else {
+    for url in extract_urls(input) {
+        let Some(cleaner_url) = clean_url(url.as_str(), providers, url_cache).await else {
             continue;
         };
 
-        if cleaner_url.eq(url.as_str()) {
+        // Compared with the parsed link, which got a trailing slash or a lowercase host like the cleaned one
+        let Ok(parsed_url) = Url::parse(url.as_str()) else {
+            continue;
+        };
+        if !cleaner_url.blocked && cleaner_url.url == CleanedUrl::new(&parsed_url, false).url {
             continue;
         }
 
@@ -104,33 +211,100 @@ async fn cleanup_comment(input: &str, providers: &[CompiledProviderDetails]) ->
     output
 }
 
-async fn clean_url(url: &str, rules: &[CompiledProviderDetails]) -> Option<String> {
+/// pr0gramm refused the comment or never got it, because the connection couldn't be opened.
+fn is_rejected_reply(error: &Error) -> bool {
+    match error {
+        Error::ProApi { .. } => true,
+        Error::Http(error) => error.is_connect(),
+        _ => false,
+    }
+}
+
+fn mark_handled(handled_messages: &HandledMessages, message_id: i32) {
+    if let Err(error) = handled_messages.insert(message_id) {
+        println!(
+            "Unable to save handled message {}. Error: {}",
+            message_id, error
+        );
+    }
+}
+
+fn mark_failed(handled_messages: &HandledMessages, message_id: i32, item_id: i32) {
+    if let Err(error) = handled_messages.insert_failed(message_id, item_id) {
+        println!(
+            "Unable to save failed message {}. Error: {}",
+            message_id, error
+        );
+    }
+}
+
+async fn clean_url(
+    url: &str,
+    rules: &CompiledProviders,
+    url_cache: &UrlCache,
+) -> Option<CleanedUrl> {
     let mut did_changes = false;
 
     let Ok(mut parsed_url) = Url::parse(url) else {
         return None;
     };
 
-    if let Some(amp_results) = remove_amp(&parsed_url).await {
+    if let Some(amp_results) = remove_amp(&parsed_url, url_cache).await {
         parsed_url = amp_results;
         did_changes = true;
     }
 
-    // if let Some(redirect_result) = remove_redirects(&parsed_url).await {
-    //     parsed_url = redirect_result;
-    //     did_changes = true;
-    // }
+    if is_shortener(&parsed_url) {
+        if let Some(redirect_result) = remove_redirects(&parsed_url, url_cache).await {
+            parsed_url = redirect_result;
+            did_changes = true;
+        }
+    }
 
-    let provider_list: Vec<&CompiledProviderDetails> = rules
-        .iter()
-        .filter(|details| details.url_pattern.is_match(url))
-        .collect();
+    // The providers have to match the unwrapped or resolved url, not the one from the comment
+    let mut current_url = parsed_url.to_string();
+
+    // Redirect targets get cleaned again, as they can be wrapped or contain tracking themselves
+    for _ in 0..MAX_PROVIDER_REDIRECTIONS {
+        match apply_providers(current_url.as_str(), &parsed_url, rules) {
+            ProviderResult::Cleaned(cleaned_url) => return Some(cleaned_url),
+            ProviderResult::Redirect(target_url) => {
+                current_url = target_url.to_string();
+                parsed_url = target_url;
+                did_changes = true;
+            }
+            ProviderResult::Blocked => return Some(CleanedUrl::blocked(current_url.as_str())),
+            ProviderResult::Untouched => break,
+        }
+    }
+
+    if did_changes {
+        Some(CleanedUrl::new(&parsed_url, false))
+    } else {
+        None
+    }
+}
+
+enum ProviderResult {
+    /// No provider is responsible for the url or one of them has an exception for it.
+    Untouched,
+    /// The url is a wrapper around another url, which was extracted from it.
+    Redirect(Url),
+    /// The whole domain is a tracking or ad domain and there is no way to get a clean url.
+    Blocked,
+    Cleaned(CleanedUrl),
+}
+
+/// Runs the url through all matching ClearURLs providers.
+fn apply_providers(url: &str, parsed_url: &Url, rules: &CompiledProviders) -> ProviderResult {
+    let keep_referral_marketing = rules.keep_referral_marketing;
+    let mut parsed_url = parsed_url.clone();
+    let mut removed_referral_marketing = false;
+
+    let provider_list = rules.matching(url);
 
     if provider_list.is_empty() {
-        if did_changes {
-            return Some(parsed_url.to_string().trim_end_matches('?').to_string());
-        }
-        return None;
+        return ProviderResult::Untouched;
     };
 
     for details in provider_list {
@@ -139,144 +313,710 @@ async fn clean_url(url: &str, rules: &[CompiledProviderDetails]) -> Option<Strin
             .iter()
             .any(|exception_regex| exception_regex.is_match(url))
         {
-            if did_changes {
-                return Some(parsed_url.to_string().trim_end_matches('?').to_string());
+            return ProviderResult::Untouched;
+        }
+
+        if let Some(redirect_url) = details.get_redirection(url) {
+            if let Ok(parsed_redirect_url) = Url::parse(redirect_url.as_str()) {
+                return ProviderResult::Redirect(parsed_redirect_url);
             }
-            return None;
         }
 
-        let pairs: Vec<(String, String)> = parsed_url
-            .query_pairs()
-            .into_owned()
-            .filter(|(key, _)| {
-                !details
-                    .rules
-                    .iter()
-                    .any(|rule_regex| rule_regex.is_match(key))
-            })
-            .collect();
+        if details.complete_provider {
+            return ProviderResult::Blocked;
+        }
+
+        let raw_cleaned_url = details.apply_raw_rules(parsed_url.as_str());
+        if raw_cleaned_url != parsed_url.as_str() {
+            if let Ok(raw_cleaned_parsed_url) = Url::parse(raw_cleaned_url.as_str()) {
+                parsed_url = raw_cleaned_parsed_url;
+            }
+        }
+
+        let mut is_removed_parameter = |key: &str| {
+            if details
+                .rules
+                .iter()
+                .any(|rule_regex| rule_regex.is_match(key))
+            {
+                return true;
+            }
+
+            if !keep_referral_marketing && details.is_referral_marketing(key) {
+                removed_referral_marketing = true;
+                return true;
+            }
+
+            false
+        };
+
+        let cleaned_query = parsed_url
+            .query()
+            .and_then(|query| filter_parameters(query, &mut is_removed_parameter));
+
+        if let Some(query) = cleaned_query {
+            parsed_url.set_query(Some(query.as_str()).filter(|query| !query.is_empty()));
+        }
 
-        parsed_url.query_pairs_mut().clear().extend_pairs(pairs);
+        let cleaned_fragment = parsed_url
+            .fragment()
+            .filter(|fragment| looks_like_query(fragment))
+            .and_then(|fragment| filter_parameters(fragment, &mut is_removed_parameter));
+
+        if let Some(fragment) = cleaned_fragment {
+            parsed_url
+                .set_fragment(Some(fragment.as_str()).filter(|fragment| !fragment.is_empty()));
+        }
     }
 
-    Some(parsed_url.to_string().trim_end_matches('?').to_string())
+    ProviderResult::Cleaned(CleanedUrl::new(&parsed_url, removed_referral_marketing))
 }
 
-async fn remove_redirects(url: &Url) -> Option<Url> {
-    let redirect_results = get_redirects(url.as_str()).await;
+/// Checks if a fragment is made of `key=value` pairs, like `#utm_source=foo&xtor=bar`, instead of being an anchor or a route.
+fn looks_like_query(fragment: &str) -> bool {
+    fragment.contains('=')
+        && fragment.split('&').all(|parameter| {
+            let key = parameter.split('=').next().unwrap_or_default();
+            !key.contains('/') && !key.contains('?')
+        })
+}
 
-    let Ok(redirects) = redirect_results else {
-        return None;
-    };
+/// Removes the parameters whose decoded key matches `is_removed` from a `key=value&key=value` string.
+/// Kept parameters stay exactly as they were. Returns `None` if nothing was removed.
+fn filter_parameters(parameters: &str, mut is_removed: impl FnMut(&str) -> bool) -> Option<String> {
+    let mut removed_any = false;
+
+    let kept_parameters: Vec<&str> = parameters
+        .split('&')
+        .filter(|parameter| {
+            let raw_key = parameter.split('=').next().unwrap_or_default();
+            let key = form_urlencoded::parse(raw_key.as_bytes())
+                .next()
+                .map(|(key, _)| key.into_owned())
+                .unwrap_or_default();
+
+            if is_removed(key.as_str()) {
+                removed_any = true;
+                return false;
+            }
 
-    let Some(result_url) = redirects.result_url else {
-        return None;
-    };
+            true
+        })
+        .collect();
+
+    removed_any.then(|| kept_parameters.join("&"))
+}
 
-    return match Url::parse(result_url.as_str()) {
-        Ok(amp_url) => Some(amp_url),
-        _ => None,
+async fn remove_redirects(url: &Url, url_cache: &UrlCache) -> Option<Url> {
+    let result_url = match url_cache.get_redirect(url.as_str()) {
+        Some(cached_result_url) => cached_result_url,
+        None => {
+            // Failed lookups aren't cached, the next comment can try again
+            let Ok(redirects) = get_resolver().resolve(url.as_str()).await else {
+                return None;
+            };
+
+            url_cache.set_redirect(url.as_str(), redirects.result_url.clone());
+            redirects.result_url
+        }
     };
+
+    Url::parse(result_url?.as_str()).ok()
 }
 
-async fn remove_amp(url: &Url) -> Option<Url> {
-    let amp_result = check_for_amp(url.as_str()).await;
+async fn remove_amp(url: &Url, url_cache: &UrlCache) -> Option<Url> {
+    match unwrap_amp(url) {
+        AmpUrl::NotAmp => None,
+        AmpUrl::Unwrapped(canonical_url) => Some(canonical_url),
+        AmpUrl::Ambiguous => lookup_canonical_url(url, url_cache).await,
+    }
+}
 
-    let Ok(amp) = amp_result else {
-        return None;
+/// Only used for urls which look like AMP, but can't be unwrapped offline.
+async fn lookup_canonical_url(url: &Url, url_cache: &UrlCache) -> Option<Url> {
+    let canonical_url = match url_cache.get_amp(url.as_str()) {
+        Some(cached_canonical_url) => cached_canonical_url,
+        None => {
+            // Failed lookups aren't cached, the next comment can try again
+            let Ok(amp) = check_for_amp(url.as_str()).await else {
+                return None;
+            };
+
+            let canonical_url = find_canonical_url(&amp);
+            url_cache.set_amp(url.as_str(), canonical_url.clone());
+            canonical_url
+        }
     };
 
-    if amp.is_empty() {
+    Url::parse(canonical_url?.as_str()).ok()
+}
+
+fn find_canonical_url(amp: &[Item]) -> Option<String> {
+    let item = amp.first()?;
+
+    // Pages which aren't AMP are linked as they are, even if they have a canonical link
+    if !item.origin.is_amp {
         return None;
     }
 
-    if let Some(x) = &amp[0].amp_canonical {
-        if !x.is_amp {
-            return match Url::parse(x.url.as_str()) {
-                Ok(amp_url) => Some(amp_url),
-                _ => None,
-            };
+    if let Some(x) = &item.amp_canonical {
+        if !x.is_amp && x.is_valid {
+            return Some(x.url.clone());
         }
     }
 
-    if let Some(x) = &amp[0].canonical {
-        if !x.is_amp {
-            return match Url::parse(x.url.as_str()) {
-                Ok(amp_url) => Some(amp_url),
-                _ => None,
-            };
+    if let Some(x) = &item.canonical {
+        if !x.is_amp && x.is_valid {
+            return Some(x.url.clone());
         }
     }
 
     None
 }
 
-fn build_response_text(links: Vec<String>) -> String {
+fn build_response_text(links: Vec<CleanedUrl>) -> String {
     if links.is_empty() {
-        "Es wurden keine Links mit Tracking gefunden.".to_string()
-    } else {
-        let mut answer = if links.len() == 1 {
-            "Hier der Link ohne Tracking:\n".to_string()
+        return "Es wurden keine Links mit Tracking gefunden.".to_string();
+    }
+
+    let (blocked_links, cleaned_links): (Vec<CleanedUrl>, Vec<CleanedUrl>) =
+        links.into_iter().partition(|link| link.blocked);
+
+    let mut answer = String::new();
+
+    if !cleaned_links.is_empty() {
+        answer += if cleaned_links.len() == 1 {
+            "Hier der Link ohne Tracking:\n"
         } else {
-            "Hier die Links ohne Tracking:\n".to_string()
+            "Hier die Links ohne Tracking:\n"
         };
 
-        links
+        cleaned_links
             .iter()
-            .for_each(|link| answer += format!("- {}\n", link).as_str());
+            .for_each(|link| answer += format_link(link).as_str());
+    }
+
+    if !blocked_links.is_empty() {
+        if !answer.is_empty() {
+            answer += "\n";
+        }
 
-        answer
+        answer += if blocked_links.len() == 1 {
+            "Achtung, dieser Link führt auf eine reine Tracking- bzw. Werbedomain. Einen sauberen Link gibt es dafür nicht:\n"
+        } else {
+            "Achtung, diese Links führen auf reine Tracking- bzw. Werbedomains. Saubere Links gibt es dafür nicht:\n"
+        };
+
+        blocked_links
+            .iter()
+            .for_each(|link| answer += format!("- {}\n", link.url).as_str());
+    }
+
+    answer
+}
+
+fn format_link(link: &CleanedUrl) -> String {
+    if link.removed_referral_marketing {
+        format!("- {} (Affiliate-Tag entfernt)\n", link.url)
+    } else {
+        format!("- {}\n", link.url)
     }
 }
 
 #[tokio::test]
+#[ignore = "Needs network access, run it with `cargo test -- --ignored`"]
 async fn test() {
-    let providers = PROVIDER.get_or_init(compile_providers()).await;
-
-    let option_with_amp_tracking = cleanup_comment("test4 https://www.google.com/amp/s/electrek.co/2018/06/19/tesla-model-3-assembly-line-inside-tent-elon-musk/amp/", providers).await;
-    // let option_with_amp_and_redirect_tracking = cleanup_comment("test5 https://www.google.com/amp/s/electrek.co/2018/06/19/tesla-model-3-assembly-line-inside-tent-elon-musk/amp/ https://bit.ly/3DlYLDG", providers).await;
-    let option_with_and_without_tracking = cleanup_comment("test1 https://duckduckgo.com/ foo https://www.phoronix.com/scan.php?page=news_item&px=Ioquake3-Auto-Updater&utm_source=feedburner&utm_medium=feed&utm_campaign=Feed%3A+Phoronix+(Phoronix) sfdfasfas", providers).await;
-    // let option_without_tracking_and_redirect = cleanup_comment("test2 https://duckduckgo.com/ bar https://www.phoronix.com/news/Ioquake3-Auto-Updater jkhpoi", providers).await;
-    let option_with_multiple_tracking = cleanup_comment("test3 https://duckduckgo.com/ buzz https://www.google.de/search?q=google&source=hp&ei=LgC7ZJb4Oq6Gxc8Pke6SuAw&ved=0ahUKEwiWx7K85qCAAxUuQ_EDHRG3BMcQ4dUDCAs&uact=5&oq=google&gs_lp=Egdnd3Mtd2l6IgZnb29nbGUyERAuGIAEGLEDGIMBGMcBGNEDMgsQABiABBixAxiDATILEAAYgAQYsQMYgwEyCxAAGIAEGLEDGIMBMgsQABiABBixAxiDATILEAAYgAQYsQMYgwEyCxAAGIAEGLEDGIMBMggQABiABBixAzIIEAAYgAQYsQMyCxAAGIAEGLEDGIMBSP4TUIMOWPAScAF4AJABAJgBQaABrgKqAQE2uAEDyAEA-AEBqAIKwgIKEAAYAxiPARjqAsICChAuGAMYjwEY6gLCAgsQLhiKBRixAxiDAcICCxAAGIoFGLEDGIMB&sclient=gws-wiz aft3ge  https://www.phoronix.com/scan.php?page=news_item&px=Ioquake3-Auto-Updater&utm_source=feedburner&utm_medium=feed&utm_campaign=Feed%3A+Phoronix+(Phoronix)", providers).await;
-    let option_with_redirect_and_tracking = cleanup_comment("test6 https://bit.ly/3DlYLDG", providers).await;
+    let providers = get_providers().await;
+    let url_cache_path = test_file_path("network_url.cache.json");
+    let url_cache = UrlCache::open(url_cache_path.as_str(), Duration::from_secs(60));
+
+    let option_with_amp_tracking = cleanup_comment("test4 https://www.google.com/amp/s/electrek.co/2018/06/19/tesla-model-3-assembly-line-inside-tent-elon-musk/amp/", &providers, &url_cache).await;
+    // let option_with_amp_and_redirect_tracking = cleanup_comment("test5 https://www.google.com/amp/s/electrek.co/2018/06/19/tesla-model-3-assembly-line-inside-tent-elon-musk/amp/ https://bit.ly/3DlYLDG", &providers, &url_cache).await;
+    let option_with_and_without_tracking = cleanup_comment("test1 https://duckduckgo.com/ foo https://www.phoronix.com/scan.php?page=news_item&px=Ioquake3-Auto-Updater&utm_source=feedburner&utm_medium=feed&utm_campaign=Feed%3A+Phoronix+(Phoronix) sfdfasfas", &providers, &url_cache).await;
+    // let option_without_tracking_and_redirect = cleanup_comment("test2 https://duckduckgo.com/ bar https://www.phoronix.com/news/Ioquake3-Auto-Updater jkhpoi", &providers, &url_cache).await;
+    let option_with_multiple_tracking = cleanup_comment("test3 https://duckduckgo.com/ buzz https://www.google.de/search?q=google&source=hp&ei=LgC7ZJb4Oq6Gxc8Pke6SuAw&ved=0ahUKEwiWx7K85qCAAxUuQ_EDHRG3BMcQ4dUDCAs&uact=5&oq=google&gs_lp=Egdnd3Mtd2l6IgZnb29nbGUyERAuGIAEGLEDGIMBGMcBGNEDMgsQABiABBixAxiDATILEAAYgAQYsQMYgwEyCxAAGIAEGLEDGIMBMgsQABiABBixAxiDATILEAAYgAQYsQMYgwEyCxAAGIAEGLEDGIMBMggQABiABBixAzIIEAAYgAQYsQMyCxAAGIAEGLEDGIMBSP4TUIMOWPAScAF4AJABAJgBQaABrgKqAQE2uAEDyAEA-AEBqAIKwgIKEAAYAxiPARjqAsICChAuGAMYjwEY6gLCAgsQLhiKBRixAxiDAcICCxAAGIoFGLEDGIMB&sclient=gws-wiz aft3ge  https://www.phoronix.com/scan.php?page=news_item&px=Ioquake3-Auto-Updater&utm_source=feedburner&utm_medium=feed&utm_campaign=Feed%3A+Phoronix+(Phoronix)", &providers, &url_cache).await;
+    let option_with_redirect_and_tracking =
+        cleanup_comment("test6 https://bit.ly/3DlYLDG", &providers, &url_cache).await;
 
     assert_eq!(option_with_and_without_tracking.len(), 1);
     assert_eq!(
-        option_with_and_without_tracking[0],
-        "https://www.phoronix.com/news/Ioquake3-Auto-Updater"
+        option_with_and_without_tracking[0].url,
+        "https://www.phoronix.com/scan.php?page=news_item&px=Ioquake3-Auto-Updater"
     );
 
     // assert_eq!(option_without_tracking_and_redirect.len(), 0);
 
     assert_eq!(option_with_multiple_tracking.len(), 2);
     assert_eq!(
-        option_with_multiple_tracking[0],
+        option_with_multiple_tracking[0].url,
         "https://www.google.de/search?q=google"
     );
     assert_eq!(
-        option_with_multiple_tracking[1],
-        "https://www.phoronix.com/news/Ioquake3-Auto-Updater"
+        option_with_multiple_tracking[1].url,
+        "https://www.phoronix.com/scan.php?page=news_item&px=Ioquake3-Auto-Updater"
     );
 
     assert_eq!(option_with_amp_tracking.len(), 1);
     assert_eq!(
-        option_with_amp_tracking[0],
+        option_with_amp_tracking[0].url,
         "https://electrek.co/2018/06/19/tesla-model-3-assembly-line-inside-tent-elon-musk/"
     );
 
     // assert_eq!(option_with_amp_and_redirect_tracking.len(), 2);
     // assert_eq!(
-    //     option_with_amp_and_redirect_tracking[0],
+    //     option_with_amp_and_redirect_tracking[0].url,
     //     "https://electrek.co/2018/06/19/tesla-model-3-assembly-line-inside-tent-elon-musk/"
     // );
     // assert_eq!(
-    //     option_with_amp_and_redirect_tracking[1],
+    //     option_with_amp_and_redirect_tracking[1].url,
     //     "https://roli.com/products/seaboard/rise2"
     // );
 
     assert_eq!(option_with_redirect_and_tracking.len(), 1);
     assert_eq!(
-        option_with_redirect_and_tracking[0],
+        option_with_redirect_and_tracking[0].url,
         "https://roli.com/products/seaboard/rise2"
     );
+
+    let _ = std::fs::remove_file(url_cache_path);
+}
+
+#[tokio::test]
+async fn test_clean_url_applies_providers_to_the_unwrapped_url() {
+    let providers = CompiledProviders::new(vec![
+        test_provider(
+            r#"{
+                "urlPattern": "^https?:\\/\\/(?:[a-z0-9-]+\\.)*?google(?:\\.[a-z]{2,}){1,}",
+                "rules": ["site", "ved"]
+            }"#,
+        ),
+        test_provider(
+            r#"{
+                "urlPattern": "^https?:\\/\\/(?:[a-z0-9-]+\\.)*?amazon(?:\\.[a-z]{2,}){1,}",
+                "rules": ["th"],
+                "referralMarketing": ["tag"]
+            }"#,
+        ),
+    ]);
+    let url_cache_path = test_file_path("unwrapped_url.cache.json");
+    let url_cache = UrlCache::open(url_cache_path.as_str(), Duration::from_secs(60));
+    url_cache.set_redirect(
+        "https://amzn.to/3xYzAbC",
+        Some("https://www.amazon.de/dp/B0TEST1234?tag=foo-21&th=1".to_string()),
+    );
+
+    let shortened = clean_url("https://amzn.to/3xYzAbC", &providers, &url_cache)
+        .await
+        .expect("Shortened url wasn't cleaned.");
+    assert_eq!(shortened.url, "https://www.amazon.de/dp/B0TEST1234");
+    assert!(shortened.removed_referral_marketing);
+
+    // The google rules must not strip `site` from the page behind the AMP wrapper
+    let unwrapped = clean_url(
+        "https://www.google.com/amp/s/www.example.com/page?site=news&id=1",
+        &providers,
+        &url_cache,
+    )
+    .await
+    .expect("AMP url wasn't unwrapped.");
+    assert_eq!(unwrapped.url, "https://www.example.com/page?site=news&id=1");
+    assert!(!unwrapped.removed_referral_marketing);
+
+    let _ = std::fs::remove_file(url_cache_path);
+}
+
+#[tokio::test]
+async fn test_unchanged_links_are_not_reported() {
+    let providers = test_providers(
+        r#"{
+            "urlPattern": "^https?:\\/\\/(?:[a-z0-9-]+\\.)*?heise\\.de",
+            "rules": ["wt_mc"]
+        }"#,
+    );
+    let url_cache_path = test_file_path("unchanged_url.cache.json");
+    let url_cache = UrlCache::open(url_cache_path.as_str(), Duration::from_secs(60));
+
+    assert_eq!(
+        cleanup_comment(
+            "Steht auf www.heise.de und https://WWW.heise.de",
+            &providers,
+            &url_cache
+        )
+        .await,
+        vec![]
+    );
+    assert_eq!(
+        cleanup_comment(
+            "www.heise.de/news/artikel.html?wt_mc=rss",
+            &providers,
+            &url_cache
+        )
+        .await,
+        vec![CleanedUrl {
+            url: "https://www.heise.de/news/artikel.html".to_string(),
+            removed_referral_marketing: false,
+            blocked: false,
+        }]
+    );
+
+    let _ = std::fs::remove_file(url_cache_path);
+}
+
+#[test]
+fn test_referral_marketing() {
+    let providers = test_providers(
+        r#"{
+            "urlPattern": "^https?:\\/\\/(?:[a-z0-9-]+\\.)*?amazon(?:\\.[a-z]{2,}){1,}",
+            "rules": ["pf_rd_[a-z]*"],
+            "referralMarketing": ["tag"]
+        }"#,
+    );
+
+    let url = "https://www.amazon.de/dp/B07PGL2ZSL?tag=foo-21&pf_rd_p=bar";
+    let ProviderResult::Cleaned(cleaned) =
+        apply_providers(url, &Url::parse(url).unwrap(), &providers)
+    else {
+        panic!("Url wasn't cleaned by the provider.");
+    };
+
+    assert_eq!(
+        cleaned,
+        CleanedUrl {
+            url: "https://www.amazon.de/dp/B07PGL2ZSL".to_string(),
+            removed_referral_marketing: true,
+            blocked: false,
+        }
+    );
+    assert_eq!(
+        build_response_text(vec![cleaned]),
+        "Hier der Link ohne Tracking:\n- https://www.amazon.de/dp/B07PGL2ZSL (Affiliate-Tag entfernt)\n"
+    );
+
+    let mut providers = providers;
+    providers.keep_referral_marketing = true;
+    let ProviderResult::Cleaned(cleaned) =
+        apply_providers(url, &Url::parse(url).unwrap(), &providers)
+    else {
+        panic!("Url wasn't cleaned by the provider.");
+    };
+
+    assert_eq!(
+        cleaned,
+        CleanedUrl {
+            url: "https://www.amazon.de/dp/B07PGL2ZSL?tag=foo-21".to_string(),
+            removed_referral_marketing: false,
+            blocked: false,
+        }
+    );
+    assert_eq!(
+        build_response_text(vec![cleaned]),
+        "Hier der Link ohne Tracking:\n- https://www.amazon.de/dp/B07PGL2ZSL?tag=foo-21\n"
+    );
+}
+
+#[test]
+fn test_complete_provider() {
+    let providers = test_providers(
+        r#"{
+            "urlPattern": "^https?:\\/\\/(?:[a-z0-9-]+\\.)*?doubleclick(?:\\.[a-z]{2,}){1,}",
+            "completeProvider": true,
+            "redirections": ["^https?:\\/\\/(?:[a-z0-9-]+\\.)*?doubleclick(?:\\.[a-z]{2,}){1,}\\/.*?adurl=([^&]*)"]
+        }"#,
+    );
+
+    let blocked_url = "https://ad.doubleclick.net/ddm/trackclk/N123";
+    assert!(matches!(
+        apply_providers(blocked_url, &Url::parse(blocked_url).unwrap(), &providers),
+        ProviderResult::Blocked
+    ));
+
+    let redirect_url = "https://ad.doubleclick.net/ddm/clk/N123;adurl=https%3A%2F%2Fexample.com%2F";
+    let ProviderResult::Redirect(target) =
+        apply_providers(redirect_url, &Url::parse(redirect_url).unwrap(), &providers)
+    else {
+        panic!("Redirect wasn't extracted by the provider.");
+    };
+    assert_eq!(target.as_str(), "https://example.com/");
+}
+
+#[test]
+fn test_fragment_parameters() {
+    let providers = test_providers(
+        r#"{
+            "urlPattern": ".*",
+            "rules": ["utm_[a-z]+", "xtor"]
+        }"#,
+    );
+
+    let cases = [
+        (
+            "https://example.com/article#utm_source=feed&utm_medium=rss",
+            "https://example.com/article",
+        ),
+        (
+            "https://example.com/article#xtor=RSS-1&page=2",
+            "https://example.com/article#page=2",
+        ),
+        (
+            "https://example.com/article?utm_source=feed#section-2",
+            "https://example.com/article#section-2",
+        ),
+        (
+            "https://example.com/app#/route?utm_source=feed",
+            "https://example.com/app#/route?utm_source=feed",
+        ),
+    ];
+
+    for (url, expected) in cases {
+        let ProviderResult::Cleaned(cleaned) =
+            apply_providers(url, &Url::parse(url).unwrap(), &providers)
+        else {
+            panic!("Url wasn't cleaned by the provider.");
+        };
+        assert_eq!(cleaned.url, expected);
+    }
+}
+
+#[test]
+fn test_query_encoding_is_preserved() {
+    let providers = test_providers(
+        r#"{
+            "urlPattern": ".*",
+            "rules": ["utm_[a-z]+", "fbclid"]
+        }"#,
+    );
+
+    let cases = [
+        (
+            "https://example.com/search?q=foo+bar&utm_source=feed&name=a%20b&flag&fbclid=123",
+            "https://example.com/search?q=foo+bar&name=a%20b&flag",
+        ),
+        (
+            "https://example.com/?z=1&utm_medium=rss&a=2",
+            "https://example.com/?z=1&a=2",
+        ),
+        (
+            "https://example.com/?utm_source=feed",
+            "https://example.com/",
+        ),
+        (
+            "https://example.com/?q=%C3%A4&x=",
+            "https://example.com/?q=%C3%A4&x=",
+        ),
+    ];
+
+    for (url, expected) in cases {
+        let ProviderResult::Cleaned(cleaned) =
+            apply_providers(url, &Url::parse(url).unwrap(), &providers)
+        else {
+            panic!("Url wasn't cleaned by the provider.");
+        };
+        assert_eq!(cleaned.url, expected);
+    }
+}
+
+#[tokio::test]
+async fn test_answer_mentions() {
+    let fake_pr0gramm = FakePr0gramm::start(
+        vec![
+            mention(11, 100, "@linkers", 0),
+            mention(21, 200, "@Linkers was soll das?", 0),
+            mention(31, 300, "@linkers", 0),
+            mention(41, 400, "@linkers", 1),
+        ],
+        vec![
+            (
+                100,
+                vec![
+                    comment(
+                        10,
+                        0,
+                        "Quelle: https://www.example.com/artikel?id=5&utm_source=newsletter",
+                    ),
+                    comment(11, 10, "@linkers"),
+                ],
+            ),
+            (
+                200,
+                vec![comment(
+                    21,
+                    0,
+                    "@Linkers was soll das? https://www.example.com/?utm_medium=rss",
+                )],
+            ),
+            (
+                300,
+                vec![
+                    comment(30, 0, "Kein Link, nur Text."),
+                    comment(31, 30, "@linkers"),
+                ],
+            ),
+            (
+                400,
+                vec![
+                    comment(40, 0, "https://www.example.com/?fbclid=123"),
+                    comment(41, 40, "@linkers"),
+                ],
+            ),
+        ],
+    )
+    .await;
+    let pro_client = fake_pr0gramm.client();
+    let providers = test_providers(r#"{"rules": ["utm_[a-z]+", "fbclid"]}"#);
+    let handled_messages_path = test_file_path("answer_mentions.json");
+    let handled_messages =
+        HandledMessages::open(handled_messages_path.as_str(), Duration::from_secs(60));
+    let url_cache_path = test_file_path("answer_mentions_url.cache.json");
+    let url_cache = UrlCache::open(url_cache_path.as_str(), Duration::from_secs(60));
+
+    answer_mentions(&pro_client, &providers, &handled_messages, &url_cache)
+        .await
+        .expect("Unable to answer mentions.");
+
+    let posted_comments: Vec<(i32, i32, String, String)> = fake_pr0gramm
+        .posted_comments()
+        .into_iter()
+        .map(|posted| {
+            (
+                posted.item_id,
+                posted.parent_id,
+                posted.comment,
+                posted.nonce,
+            )
+        })
+        .collect();
+    assert_eq!(
+        posted_comments,
+        vec![
+            (
+                100,
+                11,
+                "Hier der Link ohne Tracking:\n- https://www.example.com/artikel?id=5\n"
+                    .to_string(),
+                "test-nonce".to_string()
+            ),
+            (
+                300,
+                31,
+                "Es wurden keine Links mit Tracking gefunden.".to_string(),
+                "test-nonce".to_string()
+            ),
+        ]
+    );
+    assert!(!fake_pr0gramm
+        .requested_paths()
+        .contains(&"/api/items/info?itemId=400".to_string()));
+
+    // Answered mentions aren't answered again
+    answer_mentions(&pro_client, &providers, &handled_messages, &url_cache)
+        .await
+        .expect("Unable to answer mentions.");
+    assert_eq!(fake_pr0gramm.posted_comments().len(), 2);
+
+    let _ = std::fs::remove_file(handled_messages_path);
+    let _ = std::fs::remove_file(url_cache_path);
+}
+
+#[tokio::test]
+async fn test_failed_reply_is_retried() {
+    let too_soon = TestResponse::status(
+        429,
+        r#"{"success":false,"error":"commentTooSoon","msg":"Zu viele Kommentare"}"#,
+    );
+    let fake_pr0gramm = FakePr0gramm::start_with_post_responses(
+        vec![mention(51, 500, "@linkers", 0)],
+        vec![(
+            500,
+            vec![comment(50, 0, "Nix zu sehen."), comment(51, 50, "@linkers")],
+        )],
+        vec![
+            too_soon.clone(),
+            too_soon,
+            TestResponse::ok(r#"{"success":true,"commentId":1}"#),
+        ],
+    )
+    .await;
+    let pro_client = fake_pr0gramm.client();
+    let providers = test_providers(r#"{"rules": ["utm_[a-z]+", "fbclid"]}"#);
+    let handled_messages_path = test_file_path("failed_reply.json");
+    let handled_messages =
+        HandledMessages::open(handled_messages_path.as_str(), Duration::from_secs(60));
+    let url_cache_path = test_file_path("failed_reply_url.cache.json");
+    let url_cache = UrlCache::open(url_cache_path.as_str(), Duration::from_secs(60));
+
+    assert!(matches!(
+        pro_client.reply_comment(500, 51, "Test".to_string()).await,
+        Err(Error::ProApi { status: 429, message }) if message == "commentTooSoon, Zu viele Kommentare"
+    ));
+
+    answer_mentions(&pro_client, &providers, &handled_messages, &url_cache)
+        .await
+        .expect("Unable to answer mentions.");
+    assert_eq!(handled_messages.pending(), vec![(51, 500)]);
+
+    // The fake marked the mention as read, it is only retried because it is pending
+    answer_mentions(&pro_client, &providers, &handled_messages, &url_cache)
+        .await
+        .expect("Unable to answer mentions.");
+    assert_eq!(fake_pr0gramm.posted_comments().len(), 3);
+    assert!(handled_messages.pending().is_empty());
+
+    answer_mentions(&pro_client, &providers, &handled_messages, &url_cache)
+        .await
+        .expect("Unable to answer mentions.");
+    assert_eq!(fake_pr0gramm.posted_comments().len(), 3);
+    assert_eq!(
+        fake_pr0gramm
+            .requested_paths()
+            .iter()
+            .filter(|path| *path == "/api/inbox/all")
+            .count(),
+        1
+    );
+
+    let _ = std::fs::remove_file(handled_messages_path);
+    let _ = std::fs::remove_file(url_cache_path);
+}
+
+#[tokio::test]
+async fn test_unconfirmed_reply_is_not_retried() {
+    let fake_pr0gramm = FakePr0gramm::start_with_post_responses(
+        vec![
+            mention(61, 600, "@linkers", 0),
+            mention(71, 700, "@linkers", 0),
+        ],
+        vec![
+            (
+                600,
+                vec![comment(60, 0, "Nix zu sehen."), comment(61, 60, "@linkers")],
+            ),
+            (
+                700,
+                vec![comment(70, 0, "Auch nix."), comment(71, 70, "@linkers")],
+            ),
+        ],
+        vec![
+            TestResponse::ok("<html>Wartungsarbeiten</html>"),
+            TestResponse::ok(r#"{"success":false,"error":"commentTooSoon"}"#),
+        ],
+    )
+    .await;
+    let pro_client = fake_pr0gramm.client();
+    let providers = test_providers("{}");
+    let handled_messages_path = test_file_path("unconfirmed_reply.json");
+    let handled_messages =
+        HandledMessages::open(handled_messages_path.as_str(), Duration::from_secs(60));
+    let url_cache_path = test_file_path("unconfirmed_reply_url.cache.json");
+    let url_cache = UrlCache::open(url_cache_path.as_str(), Duration::from_secs(60));
+
+    // The first answer might be posted, only the one pr0gramm rejected is tried again
+    answer_mentions(&pro_client, &providers, &handled_messages, &url_cache)
+        .await
+        .expect("Unable to answer mentions.");
+    assert!(handled_messages.contains(61));
+    assert_eq!(handled_messages.pending(), vec![(71, 700)]);
+    assert_eq!(fake_pr0gramm.posted_comments().len(), 2);
+
+    let _ = std::fs::remove_file(handled_messages_path);
+    let _ = std::fs::remove_file(url_cache_path);
 }
@@ -0,0 +1,150 @@
+use std::env;
+use std::time::Duration;
+
+static CONFIG: once_cell::sync::OnceCell<Config> = once_cell::sync::OnceCell::new();
+
+/// Url shorteners and wrappers, which have to be requested to find out where they lead.
+const DEFAULT_SHORTENER_DOMAINS: &[&str] = &[
+    "bit.ly",
+    "bitly.com",
+    "t.co",
+    "tinyurl.com",
+    "amzn.to",
+    "amzn.eu",
+    "a.co",
+    "youtu.be",
+    "goo.gl",
+    "ow.ly",
+    "buff.ly",
+    "is.gd",
+    "v.gd",
+    "t.ly",
+    "rb.gy",
+    "cutt.ly",
+    "shorturl.at",
+    "tiny.cc",
+    "lnkd.in",
+    "fb.me",
+    "spoti.fi",
+    "trib.al",
+    "dlvr.it",
+    "ebay.us",
+    "s.click.aliexpress.com",
+    "a.aliexpress.com",
+    "vm.tiktok.com",
+    "vt.tiktok.com",
+    "redd.it",
+    "flip.it",
+    "shorturl.me",
+    "g.co",
+    "maps.app.goo.gl",
+    "on.soundcloud.com",
+    "link.chtbl.com",
+    "tinyurl.de",
+    "kurzelinks.de",
+];
+
+pub struct Config {
+    /// Keep referral marketing parameters (affiliate tags and the like) instead of removing them.
+    pub keep_referral_marketing: bool,
+    /// How often the ClearURLs rules get downloaded again while the bot is running.
+    pub rules_refresh_interval: Duration,
+    /// File the last verified ClearURLs rules are stored in.
+    pub rules_cache_path: String,
+    /// File with our own rules, which get merged into the ClearURLs rules.
+    pub local_rules_path: String,
+    /// File the results of AMP and redirect lookups are stored in.
+    pub url_cache_path: String,
+    /// How long results in the url cache are used, before the url gets looked at again.
+    pub url_cache_ttl: Duration,
+    /// How many redirects get followed before giving up on a url.
+    pub redirect_max_hops: usize,
+    /// Timeout for every single request while following redirects.
+    pub redirect_timeout: Duration,
+    /// Timeout for every single request to pr0gramm and for downloading the ClearURLs rules.
+    pub request_timeout: Duration,
+    /// A run taking longer than this is cancelled, so a stuck run doesn't stop the bot.
+    pub run_timeout: Duration,
+    /// File the ids of already answered inbox messages are stored in.
+    pub handled_messages_path: String,
+    /// How long answered inbox messages are remembered.
+    pub handled_messages_ttl: Duration,
+    /// Only urls on these domains (and their subdomains) get their redirects resolved over the network.
+    pub shortener_domains: Vec<String>,
+    /// Base url of the pr0gramm api.
+    pub pro_api_url: String,
+    /// User agent the bot sends to pr0gramm.
+    pub pro_user_agent: String,
+    /// Cookie header of the logged in bot account.
+    pub pro_cookies: Option<String>,
+    /// Nonce of the bot's session, needed for posting comments.
+    pub pro_nonce: Option<String>,
+}
+
+pub fn get_config() -> &'static Config {
+    CONFIG.get_or_init(init_config)
+}
+
+fn init_config() -> Config {
+    Config {
+        keep_referral_marketing: env_flag("LINKERS_KEEP_REFERRAL_MARKETING"),
+        rules_refresh_interval: Duration::from_secs(
+            env_number("LINKERS_RULES_REFRESH_MINUTES", 24 * 60) * 60,
+        ),
+        rules_cache_path: env::var("LINKERS_RULES_CACHE")
+            .unwrap_or_else(|_| "rules.cache.json".to_string()),
+        local_rules_path: env::var("LINKERS_LOCAL_RULES")
+            .unwrap_or_else(|_| "rules/local.json".to_string()),
+        url_cache_path: env::var("LINKERS_URL_CACHE")
+            .unwrap_or_else(|_| "url.cache.json".to_string()),
+        url_cache_ttl: Duration::from_secs(env_number("LINKERS_URL_CACHE_HOURS", 7 * 24) * 60 * 60),
+        redirect_max_hops: env_number("LINKERS_REDIRECT_MAX_HOPS", 10) as usize,
+        redirect_timeout: Duration::from_secs(env_number("LINKERS_REDIRECT_TIMEOUT_SECONDS", 5)),
+        request_timeout: Duration::from_secs(env_number("LINKERS_REQUEST_TIMEOUT_SECONDS", 30)),
+        run_timeout: Duration::from_secs(env_number("LINKERS_RUN_TIMEOUT_MINUTES", 10) * 60),
+        handled_messages_path: env::var("LINKERS_HANDLED_MESSAGES")
+            .unwrap_or_else(|_| "handled.messages.json".to_string()),
+        handled_messages_ttl: Duration::from_secs(
+            env_number("LINKERS_HANDLED_MESSAGES_DAYS", 30) * 24 * 60 * 60,
+        ),
+        shortener_domains: env_list("LINKERS_SHORTENER_DOMAINS").unwrap_or_else(|| {
+            DEFAULT_SHORTENER_DOMAINS
+                .iter()
+                .map(|domain| domain.to_string())
+                .collect()
+        }),
+        pro_api_url: env::var("LINKERS_API_URL")
+            .unwrap_or_else(|_| "https://pr0gramm.com/api".to_string()),
+        pro_user_agent: env::var("LINKERS_USER_AGENT")
+            .unwrap_or_else(|_| "Linkers Nutzer-Bot".to_string()),
+        pro_cookies: env::var("LINKERS_COOKIES").ok(),
+        pro_nonce: env::var("LINKERS_NONCE").ok(),
+    }
+}
+
+fn env_flag(name: &str) -> bool {
+    env::var(name)
+        .map(|value| matches!(value.to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+fn env_number(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(default)
+}
+
+/// Comma separated list, e.g. `bit.ly,t.co`.
+fn env_list(name: &str) -> Option<Vec<String>> {
+    let value = env::var(name).ok()?;
+
+    Some(
+        value
+            .split(',')
+            .map(|entry| entry.trim().to_lowercase())
+            .filter(|entry| !entry.is_empty())
+            .collect(),
+    )
+}
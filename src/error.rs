@@ -6,4 +6,29 @@ pub enum Error {
     Http(#[from] reqwest::Error),
     #[error("Serde wasn't able to decode the response. Serde Error: {0}")]
     Json(#[from] serde_json::Error),
+    #[error("Url couldn't be parsed. Url Error: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("Url redirects in a loop at {0}")]
+    RedirectLoop(String),
+    #[error("Url redirects more than {0} times")]
+    TooManyRedirects(usize),
+    #[error("Request to {url} was blocked, {reason}")]
+    BlockedRequest { url: String, reason: String },
+    #[error("Comment couldn't be encoded. Encoding Error: {0}")]
+    UrlEncoded(#[from] serde_urlencoded::ser::Error),
+    #[error("pr0gramm rejected the request with status {status}. Error: {message}")]
+    ProApi { status: u16, message: String },
+    #[error("Unable to access a file. IO Error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(
+        "Downloaded rules don't match the published hash. Expected: {expected}, Actual: {actual}"
+    )]
+    RulesHashMismatch { expected: String, actual: String },
+}
+
+#[derive(Error, Debug)]
+#[error("Unable to compile pattern {pattern}. Regex Error: {error}")]
+pub struct PatternError {
+    pub pattern: String,
+    pub error: regex::Error,
 }
@@ -0,0 +1,99 @@
+use regex::Regex;
+use url::Url;
+
+static URL_CANDIDATE_REGEX: once_cell::sync::OnceCell<Regex> = once_cell::sync::OnceCell::new();
+
+/// Characters that usually end a sentence or quote and are not part of a url when they are the last character.
+const TRAILING_PUNCTUATION: &[char] = &[
+    '.', ',', ';', ':', '!', '?', '\'', '"', '*', '»', '«', '“', '”', '„', '‘', '’',
+];
+
+/// Finds all links in a comment text.
+/// Links without a scheme (`www.example.com/...`) are returned with `https://` in front of them.
+pub fn extract_urls(text: &str) -> Vec<String> {
+    let candidate_regex = URL_CANDIDATE_REGEX.get_or_init(|| {
+        // Square brackets are excluded, so markdown links like `[text](url)` and `[url](url)` split up properly
+        Regex::new(r#"(?i)(?:https?://|\bwww\.)[^\s<>"\[\]]+"#)
+            .expect("Cannot build url candidate regex. Bot won't work.")
+    });
+
+    candidate_regex
+        .find_iter(text)
+        .map(|candidate| trim_candidate(candidate.as_str()))
+        .map(|candidate| {
+            if candidate.to_lowercase().starts_with("www.") {
+                format!("https://{}", candidate)
+            } else {
+                candidate.to_string()
+            }
+        })
+        .filter(|url| {
+            Url::parse(url)
+                .ok()
+                .and_then(|parsed_url| parsed_url.host_str().map(|host| host.contains('.')))
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+/// Removes trailing punctuation and closing parentheses, which belong to the surrounding text and not to the link.
+fn trim_candidate(candidate: &str) -> &str {
+    let mut url = candidate;
+
+    loop {
+        if url.ends_with(TRAILING_PUNCTUATION) {
+            url = &url[..url.len() - url.chars().last().map_or(0, char::len_utf8)];
+            continue;
+        }
+
+        // Only strip a closing parenthesis if it has no partner inside the link, e.g. wikipedia links like `Rust_(Programmiersprache)`
+        if url.ends_with(')') && url.matches(')').count() > url.matches('(').count() {
+            url = &url[..url.len() - 1];
+            continue;
+        }
+
+        return url;
+    }
+}
+
+#[test]
+fn test_extract_urls() {
+    let cases: [(&str, Vec<&str>); 8] = [
+        (
+            "Hab ich hier gefunden: https://www.spiegel.de/wirtschaft/artikel-123.html. Echt krass!",
+            vec!["https://www.spiegel.de/wirtschaft/artikel-123.html"],
+        ),
+        (
+            "Quelle (https://www.tagesschau.de/inland/meldung-100.html), falls es jemanden interessiert",
+            vec!["https://www.tagesschau.de/inland/meldung-100.html"],
+        ),
+        (
+            "Siehe https://de.wikipedia.org/wiki/Rust_(Programmiersprache) oder frag Opa",
+            vec!["https://de.wikipedia.org/wiki/Rust_(Programmiersprache)"],
+        ),
+        (
+            "(siehe https://de.wikipedia.org/wiki/Rust_(Programmiersprache))!",
+            vec!["https://de.wikipedia.org/wiki/Rust_(Programmiersprache)"],
+        ),
+        (
+            "Gibts bei [Amazon](https://www.amazon.de/dp/B07PGL2ZSL?tag=foo-21) für 20€",
+            vec!["https://www.amazon.de/dp/B07PGL2ZSL?tag=foo-21"],
+        ),
+        (
+            "Einfach mal auf www.heise.de/news/Artikel-123.html?wt_mc=rss schauen, Kollege.",
+            vec!["https://www.heise.de/news/Artikel-123.html?wt_mc=rss"],
+        ),
+        (
+            "Er meinte „https://youtu.be/dQw4w9WgXcQ?si=abc“ und https://example.com/a?b=1&c=2...",
+            vec![
+                "https://youtu.be/dQw4w9WgXcQ?si=abc",
+                "https://example.com/a?b=1&c=2",
+            ],
+        ),
+        ("Kein Link hier, nur www. und https:// allein.", vec![]),
+    ];
+
+    for (text, expected) in cases {
+        assert_eq!(extract_urls(text), expected, "Text: {}", text);
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::pro_api::{PostComment, ProApiConfig, ProClient};
+use crate::test_server::{TestResponse, TestServer};
+
+/// Fake pr0gramm api for tests. It serves a scripted inbox and posts and records the posted comments.
+/// Like pr0gramm, it marks all messages as read once the inbox was fetched.
+pub struct FakePr0gramm {
+    server: TestServer,
+}
+
+impl FakePr0gramm {
+    /// `messages` is the inbox, `posts` maps item ids to their comments.
+    pub async fn start(messages: Vec<Value>, posts: Vec<(i32, Vec<Value>)>) -> FakePr0gramm {
+        let post_response = TestResponse::ok(
+            json!({ "success": true, "commentId": 1 })
+                .to_string()
+                .as_str(),
+        );
+        FakePr0gramm::start_with_post_responses(messages, posts, vec![post_response]).await
+    }
+
+    /// Like `start`, but answers posted comments with the given responses in turn, e.g. errors.
+    pub async fn start_with_post_responses(
+        messages: Vec<Value>,
+        posts: Vec<(i32, Vec<Value>)>,
+        post_responses: Vec<TestResponse>,
+    ) -> FakePr0gramm {
+        let unread_mentions = messages
+            .iter()
+            .filter(|message| message["read"] == 0)
+            .count();
+
+        let read_messages: Vec<Value> = messages
+            .iter()
+            .cloned()
+            .map(|mut message| {
+                message["read"] = json!(1);
+                message
+            })
+            .collect();
+
+        // Every run syncs before it fetches the inbox, so only the first sync sees the unread mentions
+        let mut routes = HashMap::from([
+            (
+                "/api/user/sync?offset=9999999".to_string(),
+                vec![
+                    TestResponse::ok(
+                        json!({ "inbox": { "mentions": unread_mentions } })
+                            .to_string()
+                            .as_str(),
+                    ),
+                    TestResponse::ok(json!({ "inbox": { "mentions": 0 } }).to_string().as_str()),
+                ],
+            ),
+            (
+                "/api/inbox/all".to_string(),
+                vec![
+                    TestResponse::ok(json!({ "messages": messages }).to_string().as_str()),
+                    TestResponse::ok(json!({ "messages": read_messages }).to_string().as_str()),
+                ],
+            ),
+            ("/api/comments/post".to_string(), post_responses),
+        ]);
+        for (item_id, comments) in posts {
+            routes.insert(
+                format!("/api/items/info?itemId={}", item_id),
+                vec![TestResponse::ok(
+                    json!({ "comments": comments }).to_string().as_str(),
+                )],
+            );
+        }
+
+        FakePr0gramm {
+            server: TestServer::start_scripted(routes).await,
+        }
+    }
+
+    pub fn client(&self) -> ProClient {
+        ProClient::new(ProApiConfig {
+            base_url: self.server.url("/api"),
+            user_agent: "Linkers Test".to_string(),
+            cookies: "me=test".to_string(),
+            nonce: "test-nonce".to_string(),
+            timeout: Duration::from_secs(5),
+        })
+    }
+
+    pub fn requested_paths(&self) -> Vec<String> {
+        self.server
+            .requests()
+            .into_iter()
+            .map(|request| request.path)
+            .collect()
+    }
+
+    pub fn posted_comments(&self) -> Vec<PostComment> {
+        self.server
+            .requests()
+            .into_iter()
+            .filter(|request| request.method == "POST" && request.path == "/api/comments/post")
+            .map(|request| {
+                serde_urlencoded::from_str::<PostComment>(request.body.as_str())
+                    .expect("Unable to decode posted comment.")
+            })
+            .collect()
+    }
+}
+
+/// Inbox message for a comment with the given id. The id is the same as the one of the comment on the post.
+pub fn mention(id: i32, item_id: i32, message: &str, read: i32) -> Value {
+    json!({
+        "type": "comment",
+        "id": id,
+        "itemId": item_id,
+        "created": 1700000000,
+        "message": message,
+        "read": read,
+    })
+}
+
+pub fn comment(id: i32, parent: i32, content: &str) -> Value {
+    json!({
+        "id": id,
+        "parent": parent,
+        "content": content,
+        "created": 1700000000,
+    })
+}
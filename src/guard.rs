@@ -0,0 +1,156 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use url::{Host, Url};
+
+use crate::error::Error;
+
+/// Decides which urls from comments the bot may request itself.
+/// Every hop of a request has to be checked, as a public url can redirect into the local network.
+#[derive(Clone, Debug)]
+pub struct OutboundPolicy {
+    /// Only meant for tests against a local server
+    pub allow_private_addresses: bool,
+    /// `None` allows every port
+    pub allowed_ports: Option<Vec<u16>>,
+}
+
+/// Checked destination of a request. The request has to use these addresses,
+/// otherwise a second DNS lookup could lead somewhere else.
+#[derive(Debug)]
+pub struct OutboundTarget {
+    pub host: String,
+    pub addresses: Vec<SocketAddr>,
+}
+
+impl OutboundPolicy {
+    pub fn public_only() -> OutboundPolicy {
+        OutboundPolicy {
+            allow_private_addresses: false,
+            allowed_ports: Some(vec![80, 443]),
+        }
+    }
+
+    pub async fn check(&self, url: &Url) -> Result<OutboundTarget, Error> {
+        let blocked = |reason: &str| Error::BlockedRequest {
+            url: url.to_string(),
+            reason: reason.to_string(),
+        };
+
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(blocked("only http and https are allowed"));
+        }
+
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| blocked("port is unknown"))?;
+        if let Some(allowed_ports) = &self.allowed_ports {
+            if !allowed_ports.contains(&port) {
+                return Err(blocked("port isn't allowed"));
+            }
+        }
+
+        let (host, addresses) = match url.host() {
+            Some(Host::Domain(domain)) => {
+                let addresses: Vec<SocketAddr> = tokio::net::lookup_host((domain, port))
+                    .await
+                    .map_err(|_| blocked("host can't be resolved"))?
+                    .collect();
+                (domain.to_string(), addresses)
+            }
+            Some(Host::Ipv4(ip)) => (ip.to_string(), vec![SocketAddr::new(IpAddr::V4(ip), port)]),
+            Some(Host::Ipv6(ip)) => (ip.to_string(), vec![SocketAddr::new(IpAddr::V6(ip), port)]),
+            None => return Err(blocked("url has no host")),
+        };
+
+        if addresses.is_empty() {
+            return Err(blocked("host has no addresses"));
+        }
+
+        if !self.allow_private_addresses
+            && addresses.iter().any(|address| !is_public_ip(address.ip()))
+        {
+            return Err(blocked(
+                "host points to a private, loopback or link-local address",
+            ));
+        }
+
+        Ok(OutboundTarget { host, addresses })
+    }
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped_ip) => is_public_ipv4(mapped_ip),
+            None => is_public_ipv6(ip),
+        },
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [first, second, third, _] = ip.octets();
+
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // "This network", shared address space (carrier-grade NAT), IETF protocol assignments, benchmarking and reserved
+        || first == 0
+        || (first == 100 && (64..128).contains(&second))
+        || (first == 192 && second == 0 && third == 0)
+        || (first == 198 && (18..20).contains(&second))
+        || first >= 240)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local, link-local, documentation and NAT64 (which can point at any IPv4 address)
+        || (segments[0] & 0xfe00) == 0xfc00
+        || (segments[0] & 0xffc0) == 0xfe80
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        || (segments[0] == 0x0064 && segments[1] == 0xff9b))
+}
+
+#[tokio::test]
+async fn test_outbound_policy() {
+    let policy = OutboundPolicy::public_only();
+
+    let blocked_urls = [
+        "http://127.0.0.1/",
+        "http://localhost:80/admin",
+        "http://169.254.169.254/latest/meta-data/",
+        "http://10.0.0.1/",
+        "http://192.168.178.1/",
+        "http://172.16.0.1/",
+        "http://100.64.0.1/",
+        "http://[::1]/",
+        "http://[fd00::1]/",
+        "http://[::ffff:127.0.0.1]/",
+        "https://93.184.216.34:8443/",
+        "ftp://93.184.216.34/",
+        "file:///etc/passwd",
+    ];
+
+    for url in blocked_urls {
+        let result = policy.check(&Url::parse(url).unwrap()).await;
+        assert!(
+            matches!(result, Err(Error::BlockedRequest { .. })),
+            "{} wasn't blocked",
+            url
+        );
+    }
+
+    let target = policy
+        .check(&Url::parse("https://93.184.216.34/page").unwrap())
+        .await
+        .expect("Public address was blocked.");
+    assert_eq!(target.addresses, vec!["93.184.216.34:443".parse().unwrap()]);
+}
@@ -0,0 +1,139 @@
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::get_config;
+use crate::error::Error;
+#[cfg(test)]
+use crate::store::test_file_path;
+use crate::store::{load_json, save_json, TtlMap};
+
+/// Mentions whose answer failed this often are given up on, the error is most likely permanent.
+const MAX_REPLY_ATTEMPTS: u32 = 10;
+
+static HANDLED_MESSAGES: once_cell::sync::OnceCell<HandledMessages> =
+    once_cell::sync::OnceCell::new();
+
+/// Ids of the inbox messages the bot already took care of, so a mention is never answered twice,
+/// even if a run crashes before pr0gramm marks the inbox as read.
+/// Mentions whose answer failed are kept as pending, pr0gramm marks them as read when the inbox is
+/// fetched, so they have to be retried from here.
+/// Entries expire, the inbox only ever contains recent messages anyway.
+pub struct HandledMessages {
+    path: String,
+    ttl: Duration,
+    /// Handled messages have no value, pending ones keep what's needed to retry them.
+    message_ids: Mutex<TtlMap<i32, Option<PendingMention>>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct PendingMention {
+    item_id: i32,
+    attempts: u32,
+}
+
+pub fn get_handled_messages() -> &'static HandledMessages {
+    HANDLED_MESSAGES.get_or_init(|| {
+        let config = get_config();
+        HandledMessages::open(
+            config.handled_messages_path.as_str(),
+            config.handled_messages_ttl,
+        )
+    })
+}
+
+impl HandledMessages {
+    pub fn open(path: &str, ttl: Duration) -> HandledMessages {
+        HandledMessages {
+            path: path.to_string(),
+            ttl,
+            message_ids: Mutex::new(load_json(path)),
+        }
+    }
+
+    /// Set for handled and pending messages, the inbox doesn't need to bring up either of them again.
+    pub fn contains(&self, message_id: i32) -> bool {
+        self.message_ids().get(&message_id).is_some()
+    }
+
+    /// Mentions whose answer failed and which should be tried again, as `(message id, item id)`.
+    pub fn pending(&self) -> Vec<(i32, i32)> {
+        let mut pending: Vec<(i32, i32)> = self
+            .message_ids()
+            .iter()
+            .filter_map(|(message_id, pending)| Some((*message_id, pending.as_ref()?.item_id)))
+            .collect();
+        pending.sort_unstable();
+        pending
+    }
+
+    /// Marks the message as handled and writes the store to disk right away.
+    pub fn insert(&self, message_id: i32) -> Result<(), Error> {
+        self.set(message_id, None)
+    }
+
+    /// Counts a failed answer to the mention. It stays pending until it failed too often.
+    pub fn insert_failed(&self, message_id: i32, item_id: i32) -> Result<(), Error> {
+        let attempts = match self.message_ids().get(&message_id) {
+            Some(Some(pending)) => pending.attempts + 1,
+            _ => 1,
+        };
+
+        if attempts >= MAX_REPLY_ATTEMPTS {
+            println!(
+                "Giving up on mention {} on post {} after {} attempts.",
+                message_id, item_id, attempts
+            );
+            return self.set(message_id, None);
+        }
+
+        self.set(message_id, Some(PendingMention { item_id, attempts }))
+    }
+
+    fn set(&self, message_id: i32, pending: Option<PendingMention>) -> Result<(), Error> {
+        let mut message_ids = self.message_ids();
+        message_ids.insert(message_id, pending, self.ttl);
+        message_ids.remove_expired();
+
+        save_json(self.path.as_str(), &*message_ids)
+    }
+
+    fn message_ids(&self) -> std::sync::MutexGuard<'_, TtlMap<i32, Option<PendingMention>>> {
+        self.message_ids
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[test]
+fn test_handled_messages() {
+    let path = test_file_path("handled_messages.json");
+
+    let handled_messages = HandledMessages::open(path.as_str(), Duration::from_secs(60));
+    assert!(!handled_messages.contains(1));
+    handled_messages
+        .insert(1)
+        .expect("Unable to save handled messages.");
+    assert!(handled_messages.contains(1));
+
+    let reopened_messages = HandledMessages::open(path.as_str(), Duration::from_secs(60));
+    assert!(reopened_messages.contains(1));
+    assert!(!reopened_messages.contains(2));
+
+    reopened_messages
+        .insert_failed(2, 200)
+        .expect("Unable to save handled messages.");
+    assert!(reopened_messages.contains(2));
+    assert_eq!(reopened_messages.pending(), vec![(2, 200)]);
+
+    for _ in 1..MAX_REPLY_ATTEMPTS {
+        reopened_messages
+            .insert_failed(2, 200)
+            .expect("Unable to save handled messages.");
+    }
+    assert!(reopened_messages.contains(2));
+    assert!(reopened_messages.pending().is_empty());
+
+    let _ = std::fs::remove_file(path);
+}
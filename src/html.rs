@@ -0,0 +1,208 @@
+use regex::{Regex, RegexBuilder};
+
+static HTML_TAG_REGEX: once_cell::sync::OnceCell<Regex> = once_cell::sync::OnceCell::new();
+static META_TAG_REGEX: once_cell::sync::OnceCell<Regex> = once_cell::sync::OnceCell::new();
+static LINK_TAG_REGEX: once_cell::sync::OnceCell<Regex> = once_cell::sync::OnceCell::new();
+static ATTRIBUTE_REGEX: once_cell::sync::OnceCell<Regex> = once_cell::sync::OnceCell::new();
+static REFRESH_URL_REGEX: once_cell::sync::OnceCell<Regex> = once_cell::sync::OnceCell::new();
+static HEAD_REGEX: once_cell::sync::OnceCell<Regex> = once_cell::sync::OnceCell::new();
+static SCRIPT_REGEX: once_cell::sync::OnceCell<Regex> = once_cell::sync::OnceCell::new();
+static SCRIPT_REDIRECT_REGEX: once_cell::sync::OnceCell<Regex> = once_cell::sync::OnceCell::new();
+
+/// Finds the target of a `<meta http-equiv="refresh" content="0; url=...">` tag.
+pub fn find_meta_refresh(html: &str) -> Option<String> {
+    let meta_tag_regex = META_TAG_REGEX.get_or_init(|| build_regex(r"<meta\b[^>]*>"));
+    let refresh_url_regex = REFRESH_URL_REGEX
+        .get_or_init(|| build_regex(r#"^\s*\d*\s*[;,]?\s*url\s*=\s*['"]?([^'"]+)"#));
+
+    meta_tag_regex
+        .find_iter(html)
+        .map(|tag| attributes(tag.as_str()))
+        .filter(|attributes| has_attribute(attributes, "http-equiv", "refresh"))
+        .find_map(|attributes| {
+            let content = attribute(&attributes, "content")?;
+            let target = refresh_url_regex.captures(content.as_str())?.get(1)?;
+            Some(decode_entities(target.as_str().trim()))
+        })
+}
+
+/// Finds redirects done by scripts in the head of a page, like `location.replace("...")` or
+/// `window.location.href = "..."`. Only top-level statements count, redirects inside of functions
+/// or conditions (e.g. a login button or a mobile check) don't always run.
+pub fn find_script_redirect(html: &str) -> Option<String> {
+    let head_regex =
+        HEAD_REGEX.get_or_init(|| build_regex(r"<head\b[^>]*>(.*?)(?:</head\s*>|<body\b|$)"));
+    let script_regex =
+        SCRIPT_REGEX.get_or_init(|| build_regex(r"<script\b[^>]*>(.*?)(?:</script\s*>|$)"));
+    let script_redirect_regex = SCRIPT_REDIRECT_REGEX.get_or_init(|| {
+        build_regex(r#"\blocation(?:\.href)?\s*(?:=|\.replace\s*\()\s*["']([^"']+)["']"#)
+    });
+
+    let head = head_regex.captures(html)?.get(1)?.as_str();
+
+    script_regex
+        .captures_iter(head)
+        .filter_map(|captures| captures.get(1))
+        .find_map(|script| {
+            let script = script.as_str();
+            script_redirect_regex
+                .captures_iter(script)
+                .filter_map(|captures| captures.get(1))
+                .find(|target| brace_depth(&script[..target.start()]) == 0)
+        })
+        .map(|target| decode_entities(&target.as_str().replace("\\/", "/")))
+}
+
+/// Finds the target of a `<link rel="canonical" href="...">` tag.
+pub fn find_canonical(html: &str) -> Option<String> {
+    let link_tag_regex = LINK_TAG_REGEX.get_or_init(|| build_regex(r"<link\b[^>]*>"));
+
+    link_tag_regex
+        .find_iter(html)
+        .map(|tag| attributes(tag.as_str()))
+        .filter(|attributes| has_attribute(attributes, "rel", "canonical"))
+        .find_map(|attributes| attribute(&attributes, "href"))
+        .map(|href| decode_entities(href.trim()))
+}
+
+/// AMP pages mark themselves with `<html amp>` or `<html ⚡>`.
+pub fn is_amp_document(html: &str) -> bool {
+    let html_tag_regex = HTML_TAG_REGEX.get_or_init(|| build_regex(r"<html\b[^>]*>"));
+
+    html_tag_regex.find(html).is_some_and(|tag| {
+        tag.as_str()
+            .trim_end_matches('>')
+            .split_whitespace()
+            .skip(1)
+            .any(|attribute| {
+                let name = attribute.split('=').next().unwrap_or_default();
+                name.eq_ignore_ascii_case("amp") || name == "⚡"
+            })
+    })
+}
+
+fn build_regex(pattern: &str) -> Regex {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .dot_matches_new_line(true)
+        .build()
+        .expect("Cannot build html regex.")
+}
+
+/// Returns all attributes of a tag, with lowercase names.
+fn attributes(tag: &str) -> Vec<(String, String)> {
+    let attribute_regex = ATTRIBUTE_REGEX.get_or_init(|| {
+        build_regex(r#"([a-z][a-z0-9_:-]*)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#)
+    });
+
+    attribute_regex
+        .captures_iter(tag)
+        .filter_map(|captures| {
+            let name = captures.get(1)?.as_str().to_lowercase();
+            let value = captures
+                .get(2)
+                .or_else(|| captures.get(3))
+                .or_else(|| captures.get(4))?;
+            Some((name, value.as_str().to_string()))
+        })
+        .collect()
+}
+
+fn attribute(attributes: &[(String, String)], name: &str) -> Option<String> {
+    attributes
+        .iter()
+        .find(|(attribute_name, _)| attribute_name == name)
+        .map(|(_, value)| value.clone())
+}
+
+fn has_attribute(attributes: &[(String, String)], name: &str, value: &str) -> bool {
+    attribute(attributes, name).is_some_and(|attribute_value| {
+        attribute_value
+            .split_whitespace()
+            .any(|part| part.eq_ignore_ascii_case(value))
+    })
+}
+
+/// Counts the open braces of a piece of javascript, braces in string literals are skipped.
+fn brace_depth(script: &str) -> i32 {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+
+    for c in script.chars() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(open_quote) if c == open_quote => quote = None,
+            Some(_) => {}
+            None => match c {
+                '"' | '\'' | '`' => quote = Some(c),
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
+            },
+        }
+    }
+
+    depth
+}
+
+fn decode_entities(value: &str) -> String {
+    value
+        .replace("&amp;", "&")
+        .replace("&#38;", "&")
+        .replace("&#x2F;", "/")
+        .replace("&#47;", "/")
+}
+
+#[test]
+fn test_html_targets() {
+    assert_eq!(
+        find_meta_refresh(
+            r#"<html><head><META HTTP-EQUIV="Refresh" CONTENT="0; URL='https://example.com/a?b=1&amp;c=2'"></head></html>"#
+        ),
+        Some("https://example.com/a?b=1&c=2".to_string())
+    );
+    assert_eq!(
+        find_meta_refresh(r#"<meta content="5;url=/next" http-equiv=refresh>"#),
+        Some("/next".to_string())
+    );
+    assert_eq!(find_meta_refresh(r#"<meta charset="utf-8">"#), None);
+
+    assert_eq!(
+        find_script_redirect(
+            r#"<head><script>window.location.replace("https:\/\/example.com\/target");</script></head>"#
+        ),
+        Some("https://example.com/target".to_string())
+    );
+    assert_eq!(
+        find_script_redirect(
+            r#"<html><head><script src="/app.js"></script><script>var s = "{"; location.href = 'https://example.com/other';</script>"#
+        ),
+        Some("https://example.com/other".to_string())
+    );
+    assert_eq!(
+        find_script_redirect(
+            r#"<head></head><body><script>location.replace("https://example.com/login");</script></body>"#
+        ),
+        None
+    );
+    assert_eq!(
+        find_script_redirect(
+            r#"<head><script>function login() { location.href = "/login"; } if (mobile) { location.replace("https://m.example.com/"); }</script></head>"#
+        ),
+        None
+    );
+
+    assert_eq!(
+        find_canonical(
+            r#"<link rel="stylesheet" href="/style.css"><link href="https://example.com/article" rel="canonical" />"#
+        ),
+        Some("https://example.com/article".to_string())
+    );
+    assert_eq!(find_canonical(r#"<link rel="amphtml" href="/amp">"#), None);
+
+    assert!(is_amp_document(r#"<!doctype html><html amp lang="de">"#));
+    assert!(is_amp_document("<html ⚡>"));
+    assert!(!is_amp_document(r#"<html lang="de" class="amp-free">"#));
+}
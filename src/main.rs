@@ -1,25 +1,65 @@
+use std::time::Instant;
+
+use tokio::time::MissedTickBehavior;
+
+mod amp;
+mod cache;
 mod cleaner;
+mod config;
 mod error;
+mod extractor;
+#[cfg(test)]
+mod fake_pr0gramm;
+mod guard;
+mod handled;
+mod html;
 mod pro_api;
 mod providers;
+mod resolver;
+mod store;
+#[cfg(test)]
+mod test_server;
 mod utils_api;
 
 #[tokio::main]
 async fn main() {
-    let mut interval_timer = tokio::time::interval(
-        chrono::Duration::seconds(60)
-            .to_std()
-            .expect("Unable to build interval timer. Bot won't start."),
-    );
+    let run_interval = chrono::Duration::seconds(60)
+        .to_std()
+        .expect("Unable to build interval timer. Bot won't start.");
+    let mut interval_timer = tokio::time::interval(run_interval);
+    // Ticks which pass while a run is still active are dropped, there's only ever one run at a time
+    interval_timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    tokio::spawn(providers::run_provider_refresh(
+        config::get_config().rules_refresh_interval,
+    ));
 
     loop {
         interval_timer.tick().await;
 
-        tokio::spawn(async {
-            let run_result = cleaner::run_linkers().await;
-            if let Err(error) = run_result {
-                println!("Linkers wasn't able to run. Error: {}", error)
-            };
-        });
+        let run_start = Instant::now();
+
+        // The run is spawned, so a panic only ends this run and not the bot.
+        // The timeout cancels a stuck run, otherwise the next ticks would never start.
+        let run_timeout = config::get_config().run_timeout;
+        let run_result =
+            tokio::spawn(tokio::time::timeout(run_timeout, cleaner::run_linkers())).await;
+        match run_result {
+            Ok(Ok(Err(error))) => println!("Linkers wasn't able to run. Error: {}", error),
+            Ok(Err(_)) => println!(
+                "Linkers run took longer than {:?} and was cancelled.",
+                run_timeout
+            ),
+            Err(error) => println!("Linkers run crashed. Error: {}", error),
+            Ok(Ok(Ok(()))) => {}
+        }
+
+        let run_duration = run_start.elapsed();
+        if run_duration > run_interval {
+            println!(
+                "Linkers run took {:?}, which is longer than the interval of {:?}. Skipped the ticks in between.",
+                run_duration, run_interval
+            );
+        }
     }
 }
@@ -1,18 +1,30 @@
-use std::env;
+use std::time::Duration;
 
 use http::header::{CONTENT_TYPE, COOKIE, USER_AGENT};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 
+use crate::config::{get_config, Config};
 use crate::error::Error;
 
 static PRO_CLIENT: once_cell::sync::OnceCell<ProClient> = once_cell::sync::OnceCell::new();
 
-struct ProClient {
+pub struct ProClient {
     http_client: Client,
-    cookies: String,
-    nonce: String,
-    user_agent: String,
+    config: ProApiConfig,
+}
+
+/// Where and as whom the bot talks to pr0gramm. Tests point `base_url` to a local fake server.
+pub struct ProApiConfig {
+    /// Base url of the api, e.g. `https://pr0gramm.com/api`
+    pub base_url: String,
+    pub user_agent: String,
+    /// Cookie header of the logged in bot account
+    pub cookies: String,
+    /// Nonce of the session, needed for posting comments
+    pub nonce: String,
+    /// Timeout for every single request
+    pub timeout: Duration,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -45,6 +57,28 @@ pub struct Post {
     pub comments: Vec<Comment>,
 }
 
+/// Answer to a posted comment. Errors have `error` set to a code like `commentTooSoon` and `msg` to a description.
+#[derive(Debug, Deserialize)]
+struct PostCommentResponse {
+    success: Option<bool>,
+    error: Option<String>,
+    msg: Option<String>,
+}
+
+impl PostCommentResponse {
+    fn is_rejected(&self) -> bool {
+        self.success == Some(false) || self.error.is_some()
+    }
+
+    fn error_message(self) -> String {
+        self.error
+            .into_iter()
+            .chain(self.msg)
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Inbox {
     pub mentions: i32,
@@ -66,110 +100,153 @@ pub struct PostComment {
     pub nonce: String,
 }
 
-pub async fn get_latest_messages() -> Result<MessageCollection, Error> {
-    let client = PRO_CLIENT.get_or_init(init_pro_client);
-
-    let resp = client
-        .http_client
-        .get("https://pr0gramm.com/api/inbox/all")
-        .header(COOKIE, &client.cookies)
-        .header(USER_AGENT, &client.user_agent)
-        .send()
-        .await?
-        .text()
-        .await?;
-
-    Ok(serde_json::from_str::<MessageCollection>(resp.as_str())?)
+impl ProApiConfig {
+    /// Takes the api settings from the bot's config, it can't run without cookies and nonce.
+    pub fn from_config(config: &Config) -> ProApiConfig {
+        ProApiConfig {
+            base_url: config.pro_api_url.clone(),
+            user_agent: config.pro_user_agent.clone(),
+            cookies: config
+                .pro_cookies
+                .clone()
+                .expect("Cookies not set. Exiting as the bot won't be able to run."),
+            nonce: config
+                .pro_nonce
+                .clone()
+                .expect("Nonce not set. Exiting as the bot won't be able to run."),
+            timeout: config.request_timeout,
+        }
+    }
 }
 
-pub async fn get_post(item_id: i32) -> Result<Post, Error> {
-    let client = PRO_CLIENT.get_or_init(init_pro_client);
-
-    let resp = client
-        .http_client
-        .get(format!(
-            "https://pr0gramm.com/api/items/info?itemId={}",
-            item_id
-        ))
-        .header(COOKIE, &client.cookies)
-        .header(USER_AGENT, &client.user_agent)
-        .send()
-        .await?
-        .text()
-        .await?;
-
-    Ok(serde_json::from_str::<Post>(resp.as_str())?)
+pub fn get_pro_client() -> &'static ProClient {
+    PRO_CLIENT.get_or_init(|| ProClient::new(ProApiConfig::from_config(get_config())))
 }
 
-pub async fn reply_comment(item_id: i32, parent_comment: i32, message: String) {
-    let client = PRO_CLIENT.get_or_init(init_pro_client);
-
-    let comment = serde_urlencoded::to_string(PostComment {
-        comment: message,
-        parent_id: parent_comment,
-        item_id,
-        nonce: client.nonce.to_string(),
-    });
-
-    let Ok(comment_text) = comment else {
-        println!("Unable to encode comment: Error: {:?}", comment);
-        return;
-    };
-
-    let response = client
-        .http_client
-        .post("https://pr0gramm.com/api/comments/post")
-        .header(COOKIE, &client.cookies)
-        .header(USER_AGENT, &client.user_agent)
-        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
-        .body(comment_text)
-        .send()
-        .await;
-
-    match response {
-        Ok(res) => {
-            println!(
-                "Posted comment on post {} with status code: {:?}",
-                item_id,
-                res.status().as_u16()
-            )
-        }
-        Err(res) => {
-            println!(
-                "Error while posting comment on post {} with error: {}",
-                item_id, res
-            )
+impl ProClient {
+    pub fn new(config: ProApiConfig) -> ProClient {
+        ProClient {
+            http_client: Client::builder()
+                .timeout(config.timeout)
+                .build()
+                .expect("Unable to build pr0gramm api client."),
+            config,
         }
     }
-}
 
-pub async fn has_unread_messages() -> Result<bool, Error> {
-    let client = PRO_CLIENT.get_or_init(init_pro_client);
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.config.base_url.trim_end_matches('/'), path)
+    }
 
-    let resp = client
-        .http_client
-        .get("https://pr0gramm.com/api/user/sync?offset=9999999")
-        .header(COOKIE, &client.cookies)
-        .header(USER_AGENT, &client.user_agent)
-        .send()
-        .await?
-        .text()
-        .await?;
+    pub async fn get_latest_messages(&self) -> Result<MessageCollection, Error> {
+        let resp = self
+            .http_client
+            .get(self.url("inbox/all"))
+            .header(COOKIE, &self.config.cookies)
+            .header(USER_AGENT, &self.config.user_agent)
+            .send()
+            .await?
+            .text()
+            .await?;
+
+        Ok(serde_json::from_str::<MessageCollection>(resp.as_str())?)
+    }
 
-    println!("Sync Response: {}", resp.as_str());
+    pub async fn get_post(&self, item_id: i32) -> Result<Post, Error> {
+        let resp = self
+            .http_client
+            .get(self.url(format!("items/info?itemId={}", item_id).as_str()))
+            .header(COOKIE, &self.config.cookies)
+            .header(USER_AGENT, &self.config.user_agent)
+            .send()
+            .await?
+            .text()
+            .await?;
+
+        Ok(serde_json::from_str::<Post>(resp.as_str())?)
+    }
 
-    Ok(serde_json::from_str::<Sync>(resp.as_str())?.inbox.mentions > 0)
-}
+    /// Posts a comment. Fails with `Error::ProApi` if pr0gramm clearly rejected the comment, through the status code
+    /// or the answer. A success status with an answer that can't be read might still mean the comment was posted,
+    /// so that only gets logged.
+    pub async fn reply_comment(
+        &self,
+        item_id: i32,
+        parent_comment: i32,
+        message: String,
+    ) -> Result<(), Error> {
+        let comment_text = serde_urlencoded::to_string(PostComment {
+            comment: message,
+            parent_id: parent_comment,
+            item_id,
+            nonce: self.config.nonce.to_string(),
+        })?;
+
+        let response = self
+            .http_client
+            .post(self.url("comments/post"))
+            .header(COOKIE, &self.config.cookies)
+            .header(USER_AGENT, &self.config.user_agent)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(comment_text)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let resp = match response.text().await {
+            Ok(resp) => resp,
+            Err(error) if status.is_success() => {
+                println!(
+                    "Unable to read the answer to the comment on post {}, it might be posted. Error: {}",
+                    item_id, error
+                );
+                return Ok(());
+            }
+            Err(error) => return Err(error.into()),
+        };
+        let post_response = serde_json::from_str::<PostCommentResponse>(resp.as_str()).ok();
+
+        match post_response {
+            Some(post_response) if !status.is_success() || post_response.is_rejected() => {
+                Err(Error::ProApi {
+                    status: status.as_u16(),
+                    message: post_response.error_message(),
+                })
+            }
+            None if !status.is_success() => Err(Error::ProApi {
+                status: status.as_u16(),
+                message: resp,
+            }),
+            Some(PostCommentResponse {
+                success: Some(true),
+                ..
+            }) => {
+                println!("Posted comment on post {}", item_id);
+                Ok(())
+            }
+            _ => {
+                println!(
+                    "Unknown answer to the comment on post {}, it might be posted. Answer: {}",
+                    item_id, resp
+                );
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn has_unread_messages(&self) -> Result<bool, Error> {
+        let resp = self
+            .http_client
+            .get(self.url("user/sync?offset=9999999"))
+            .header(COOKIE, &self.config.cookies)
+            .header(USER_AGENT, &self.config.user_agent)
+            .send()
+            .await?
+            .text()
+            .await?;
 
-fn init_pro_client() -> ProClient {
-    let client = Client::new();
+        println!("Sync Response: {}", resp.as_str());
 
-    ProClient {
-        http_client: client,
-        cookies: env::var("LINKERS_COOKIES")
-            .expect("Cookies not set. Exiting as the bot won't be able to run."),
-        nonce: env::var("LINKERS_NONCE")
-            .expect("Nonce not set. Exiting as the bot won't be able to run."),
-        user_agent: "Linkers Nutzer-Bot".to_string(),
+        Ok(serde_json::from_str::<Sync>(resp.as_str())?.inbox.mentions > 0)
     }
 }
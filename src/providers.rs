@@ -1,7 +1,30 @@
-use regex::Regex;
+use regex::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
 use reqwest::Client;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+
+use crate::config::get_config;
+use crate::error::{Error, PatternError};
+
+const URL_PATTERN_SET_SIZE_LIMIT: usize = 64 * 1024 * 1024;
+const RULES_URL: &str = "https://gitlab.com/ClearURLs/rules/-/raw/master/data.min.json";
+const RULES_HASH_URL: &str = "https://gitlab.com/ClearURLs/rules/-/raw/master/rules.min.hash";
+/// Hand-maintained subset of the ClearURLs rules, used if they can't be downloaded on startup.
+/// Replace it together with its hash, a test checks that both belong together:
+/// `curl -o rules/data.min.json https://gitlab.com/ClearURLs/rules/-/raw/master/data.min.json`
+/// `curl -o rules/data.min.hash https://gitlab.com/ClearURLs/rules/-/raw/master/rules.min.hash`
+const BUNDLED_RULES: &str = include_str!("../rules/data.min.json");
+#[cfg(test)]
+const BUNDLED_RULES_HASH: &str = include_str!("../rules/data.min.hash");
+
+static FAILED_PROVIDERS: AtomicUsize = AtomicUsize::new(0);
+static PROVIDERS: async_once_cell::OnceCell<RwLock<Arc<CompiledProviders>>> =
+    async_once_cell::OnceCell::new();
 
 #[derive(Debug, Deserialize)]
 pub struct ProviderDetails {
@@ -29,71 +52,551 @@ pub struct CompiledProviderDetails {
     raw_rules: Vec<Regex>,
     pub exceptions: Vec<Regex>,
     redirections: Vec<Regex>,
+    // Part of the ClearURLs format, but only the browser extension needs it to redirect whole pages
+    #[allow(dead_code)]
     force_redirection: bool,
 }
 
+/// All active providers, with their url patterns combined into one set,
+/// so finding the providers for a url doesn't run every single pattern.
+#[derive(Debug)]
+pub struct CompiledProviders {
+    providers: Vec<CompiledProviderDetails>,
+    url_patterns: Option<RegexSet>,
+    /// Keep referral marketing parameters (affiliate tags and the like) instead of removing them.
+    pub keep_referral_marketing: bool,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Providers {
     pub providers: HashMap<String, ProviderDetails>,
 }
 
 impl CompiledProviderDetails {
-    pub fn new(details: &ProviderDetails) -> Result<CompiledProviderDetails, regex::Error> {
+    pub fn new(details: &ProviderDetails) -> Result<CompiledProviderDetails, PatternError> {
         Ok(CompiledProviderDetails {
-            url_pattern: Regex::new(details.url_pattern.as_str())?,
+            url_pattern: compile_pattern(details.url_pattern.as_str(), Regex::new)?,
             complete_provider: details.complete_provider,
-            rules: details
-                .rules
-                .iter()
-                .map(|x| Regex::new(x.as_str()))
-                .collect::<Result<Vec<_>, _>>()?,
-            referral_marketing: details
-                .referral_marketing
-                .iter()
-                .map(|x| Regex::new(x.as_str()))
-                .collect::<Result<Vec<_>, _>>()?,
-            raw_rules: details
-                .raw_rules
-                .iter()
-                .map(|x| Regex::new(x.as_str()))
-                .collect::<Result<Vec<_>, _>>()?,
-            exceptions: details
-                .exceptions
-                .iter()
-                .map(|x| Regex::new(x.as_str()))
-                .collect::<Result<Vec<_>, _>>()?,
-            redirections: details
-                .redirections
-                .iter()
-                .map(|x| Regex::new(x.as_str()))
-                .collect::<Result<Vec<_>, _>>()?,
+            rules: compile_patterns(&details.rules, build_parameter_regex)?,
+            referral_marketing: compile_patterns(
+                &details.referral_marketing,
+                build_parameter_regex,
+            )?,
+            raw_rules: compile_patterns(&details.raw_rules, build_case_insensitive_regex)?,
+            exceptions: compile_patterns(&details.exceptions, Regex::new)?,
+            redirections: compile_patterns(&details.redirections, build_case_insensitive_regex)?,
             force_redirection: details.force_redirection,
         })
     }
+
+    pub fn is_referral_marketing(&self, parameter: &str) -> bool {
+        self.referral_marketing
+            .iter()
+            .any(|referral_regex| referral_regex.is_match(parameter))
+    }
+
+    /// Returns the percent-decoded first capture group of the first matching redirection rule.
+    pub fn get_redirection(&self, url: &str) -> Option<String> {
+        let target = self
+            .redirections
+            .iter()
+            .find_map(|redirection| redirection.captures(url)?.get(1))?;
+
+        // Decoded exactly once like the ClearURLs extension does, encoded characters of the target itself have to stay
+        let mut decoded = urlencoding::decode(target.as_str())
+            .map(|decoded| decoded.into_owned())
+            .unwrap_or_else(|_| target.as_str().to_string());
+
+        if !decoded.starts_with("http") {
+            decoded = format!("http://{}", decoded);
+        }
+
+        Some(decoded)
+    }
+
+    /// Removes every match of the raw rules from the whole url string, the same way the ClearURLs extension does.
+    pub fn apply_raw_rules(&self, url: &str) -> String {
+        self.raw_rules
+            .iter()
+            .fold(url.to_string(), |url, raw_rule| {
+                raw_rule.replace_all(url.as_str(), "").into_owned()
+            })
+    }
 }
 
-pub async fn compile_providers() -> Vec<CompiledProviderDetails> {
-    let resp = Client::new()
-        .get("https://gitlab.com/ClearURLs/rules/-/raw/master/data.min.json")
-        .send()
+impl CompiledProviders {
+    pub fn new(providers: Vec<CompiledProviderDetails>) -> CompiledProviders {
+        let url_patterns =
+            RegexSetBuilder::new(providers.iter().map(|details| details.url_pattern.as_str()))
+                .size_limit(URL_PATTERN_SET_SIZE_LIMIT)
+                .build();
+
+        // Matching still works without the set, it's just slower
+        if let Err(error) = &url_patterns {
+            println!(
+                "Unable to combine the url patterns of the providers. Error: {}",
+                error
+            );
+        }
+
+        CompiledProviders {
+            providers,
+            url_patterns: url_patterns.ok(),
+            keep_referral_marketing: false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.providers.len()
+    }
+
+    /// Returns the providers whose url pattern matches the url, in the order they were added.
+    pub fn matching<'a>(&'a self, url: &str) -> Vec<&'a CompiledProviderDetails> {
+        match &self.url_patterns {
+            Some(url_patterns) => url_patterns
+                .matches(url)
+                .into_iter()
+                .map(|index| &self.providers[index])
+                .collect(),
+            None => self
+                .providers
+                .iter()
+                .filter(|details| details.url_pattern.is_match(url))
+                .collect(),
+        }
+    }
+}
+
+fn compile_pattern(
+    pattern: &str,
+    build: impl Fn(&str) -> Result<Regex, regex::Error>,
+) -> Result<Regex, PatternError> {
+    build(pattern).map_err(|error| PatternError {
+        pattern: pattern.to_string(),
+        error,
+    })
+}
+
+fn compile_patterns(
+    patterns: &[String],
+    build: impl Fn(&str) -> Result<Regex, regex::Error>,
+) -> Result<Vec<Regex>, PatternError> {
+    patterns
+        .iter()
+        .map(|pattern| compile_pattern(pattern.as_str(), &build))
+        .collect()
+}
+
+fn build_case_insensitive_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern).case_insensitive(true).build()
+}
+
+/// Parameter rules have to match the whole parameter name, the same way the ClearURLs extension applies them.
+fn build_parameter_regex(rule: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(format!("^(?:{})$", rule).as_str())
+        .case_insensitive(true)
+        .build()
+}
+
+async fn compile_providers() -> CompiledProviders {
+    let providers = match download_providers().await {
+        Ok(providers) => {
+            println!("Using ClearURLs rules downloaded from {}", RULES_URL);
+            providers
+        }
+        Err(error) => {
+            println!("Unable to download ClearURLs rules. Error: {}", error);
+            match cached_providers() {
+                Some(providers) => {
+                    println!(
+                        "Using cached ClearURLs rules from {}",
+                        get_config().rules_cache_path
+                    );
+                    providers
+                }
+                None => {
+                    println!("Using the bundled subset of the ClearURLs rules.");
+                    bundled_providers()
+                }
+            }
+        }
+    };
+
+    let mut compiled_providers = compile(with_local_providers(providers));
+    compiled_providers.keep_referral_marketing = get_config().keep_referral_marketing;
+    compiled_providers
+}
+
+fn compile(providers: Providers) -> CompiledProviders {
+    let mut compiled_providers = Vec::with_capacity(providers.providers.len());
+    let mut failed_providers = 0;
+
+    for (name, details) in &providers.providers {
+        match CompiledProviderDetails::new(details) {
+            Ok(compiled_provider) => compiled_providers.push(compiled_provider),
+            Err(error) => {
+                println!("Skipping provider {}. {}", name, error);
+                failed_providers += 1;
+            }
+        }
+    }
+
+    println!(
+        "{} providers are active, {} failed to compile.",
+        compiled_providers.len(),
+        failed_providers
+    );
+    FAILED_PROVIDERS.store(failed_providers, Ordering::Relaxed);
+
+    CompiledProviders::new(compiled_providers)
+}
+
+/// Number of providers left out of the active rules, because one of their patterns didn't compile.
+pub fn failed_provider_count() -> usize {
+    FAILED_PROVIDERS.load(Ordering::Relaxed)
+}
+
+async fn provider_store() -> &'static RwLock<Arc<CompiledProviders>> {
+    PROVIDERS
+        .get_or_init(async { RwLock::new(Arc::new(compile_providers().await)) })
         .await
-        .expect("Unable to load rules, bot cannot be started.")
-        .text()
+}
+
+/// Returns the currently active providers. The rules are loaded on the first call.
+pub async fn get_providers() -> Arc<CompiledProviders> {
+    provider_store()
+        .await
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// Downloads the latest rules and swaps them in. The active rules stay in place if anything goes wrong,
+/// including a download that doesn't match the published hash.
+pub async fn refresh_providers() -> Result<usize, Error> {
+    let compiled_providers = Arc::new(compile(with_local_providers(download_providers().await?)));
+    let provider_count = compiled_providers.len();
+
+    *provider_store()
         .await
-        .expect("Unable to load rules, bot cannot be started.");
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = compiled_providers;
+
+    Ok(provider_count)
+}
+
+pub async fn run_provider_refresh(refresh_interval: Duration) {
+    let mut interval_timer = tokio::time::interval(refresh_interval);
+    // The first tick completes immediately, but the rules were just loaded on startup
+    interval_timer.tick().await;
+
+    loop {
+        interval_timer.tick().await;
+
+        match refresh_providers().await {
+            Ok(provider_count) => println!(
+                "Reloaded ClearURLs rules, {} providers are active, {} failed to compile.",
+                provider_count,
+                failed_provider_count()
+            ),
+            Err(error) => println!(
+                "Unable to reload ClearURLs rules, keeping the current ones. Error: {}",
+                error
+            ),
+        }
+    }
+}
 
-    let providers = serde_json::from_str::<Providers>(resp.as_str())
-        .expect("Unable to load rules, bot cannot be started.");
+/// Downloads the rules and checks them against the published hash.
+/// Verified rules are written to the cache, so they can be used if the next download fails.
+async fn download_providers() -> Result<Providers, Error> {
+    let client = Client::builder()
+        .timeout(get_config().request_timeout)
+        .build()?;
 
-    let compiled_providers: Vec<CompiledProviderDetails> = providers
+    let rules = client
+        .get(RULES_URL)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+
+    let expected_hash = client
+        .get(RULES_HASH_URL)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    verify_rules_hash(&rules, expected_hash.as_str())?;
+
+    let providers = serde_json::from_slice::<Providers>(&rules)?;
+
+    if let Err(error) = fs::write(get_config().rules_cache_path.as_str(), &rules) {
+        println!("Unable to write ClearURLs rules cache. Error: {}", error);
+    }
+
+    Ok(providers)
+}
+
+fn verify_rules_hash(rules: &[u8], expected_hash: &str) -> Result<(), Error> {
+    let actual_hash = format!("{:x}", Sha256::digest(rules));
+    let expected_hash = expected_hash.trim();
+
+    if !actual_hash.eq_ignore_ascii_case(expected_hash) {
+        return Err(Error::RulesHashMismatch {
+            expected: expected_hash.to_string(),
+            actual: actual_hash,
+        });
+    }
+
+    Ok(())
+}
+
+fn cached_providers() -> Option<Providers> {
+    let rules = fs::read(get_config().rules_cache_path.as_str()).ok()?;
+
+    match serde_json::from_slice::<Providers>(&rules) {
+        Ok(providers) => Some(providers),
+        Err(error) => {
+            println!("Unable to read ClearURLs rules cache. Error: {}", error);
+            None
+        }
+    }
+}
+
+/// Loads the locally maintained rules, which use the same format as the ClearURLs rules.
+fn local_providers() -> Option<Providers> {
+    let path = get_config().local_rules_path.as_str();
+    let rules = fs::read(path).ok()?;
+
+    match serde_json::from_slice::<Providers>(&rules) {
+        Ok(providers) => Some(providers),
+        Err(error) => {
+            println!("Unable to read local rules from {}. Error: {}", path, error);
+            None
+        }
+    }
+}
+
+fn with_local_providers(mut providers: Providers) -> Providers {
+    if let Some(local_providers) = local_providers() {
+        merge_providers(&mut providers, local_providers);
+    }
+
+    providers
+}
+
+/// Adds the local providers to the ClearURLs providers. Local providers replace ClearURLs providers with the same name.
+fn merge_providers(providers: &mut Providers, local_providers: Providers) {
+    for (name, details) in local_providers.providers {
+        if providers.providers.insert(name.clone(), details).is_some() {
+            println!("Local rules replace the ClearURLs provider {}", name);
+        } else {
+            println!("Local rules add the provider {}", name);
+        }
+    }
+}
+
+fn bundled_providers() -> Providers {
+    serde_json::from_str::<Providers>(BUNDLED_RULES)
+        .expect("Unable to load bundled rules, bot cannot be started.")
+}
+
+/// Builds a provider for tests. Fields missing in `details` are empty and the url pattern matches everything.
+#[cfg(test)]
+pub fn test_provider(details: &str) -> CompiledProviderDetails {
+    let mut details_json = serde_json::json!({
+        "urlPattern": ".*",
+        "completeProvider": false,
+        "rules": [],
+        "referralMarketing": [],
+        "rawRules": [],
+        "exceptions": [],
+        "redirections": [],
+        "forceRedirection": false
+    });
+    let overrides =
+        serde_json::from_str::<serde_json::Value>(details).expect("Unable to parse test provider.");
+    for (key, value) in overrides
+        .as_object()
+        .expect("Test provider isn't an object.")
+    {
+        details_json[key] = value.clone();
+    }
+
+    let details = serde_json::from_value::<ProviderDetails>(details_json)
+        .expect("Unable to parse test provider.");
+    CompiledProviderDetails::new(&details).expect("Unable to compile test provider.")
+}
+
+#[cfg(test)]
+pub fn test_providers(details: &str) -> CompiledProviders {
+    CompiledProviders::new(vec![test_provider(details)])
+}
+
+#[test]
+fn test_raw_rules() {
+    let provider = test_provider(
+        r#"{
+            "urlPattern": "^https?:\\/\\/(?:[a-z0-9-]+\\.)*?amazon(?:\\.[a-z]{2,}){1,}",
+            "rules": ["tag"],
+            "rawRules": ["\\/ref=[^/?]*"]
+        }"#,
+    );
+
+    assert_eq!(
+        provider.apply_raw_rules("https://www.amazon.de/dp/B07PGL2ZSL/ref=sr_1_1?keywords=foo"),
+        "https://www.amazon.de/dp/B07PGL2ZSL?keywords=foo"
+    );
+    assert_eq!(
+        provider.apply_raw_rules("https://www.amazon.de/dp/B07PGL2ZSL"),
+        "https://www.amazon.de/dp/B07PGL2ZSL"
+    );
+}
+
+#[test]
+fn test_redirections() {
+    let provider = test_provider(
+        r#"{
+            "urlPattern": "^https?:\\/\\/(?:[a-z0-9-]+\\.)*?google(?:\\.[a-z]{2,}){1,}",
+            "rules": ["ved", "usg"],
+            "redirections": ["^https?:\\/\\/(?:[a-z0-9-]+\\.)*?google(?:\\.[a-z]{2,}){1,}\\/url\\?.*?(?:url|q)=(https?[^&]+)"]
+        }"#,
+    );
+
+    assert_eq!(
+        provider.get_redirection(
+            "https://www.google.com/url?sa=t&url=https%3A%2F%2Fexample.com%2Fpage%3Fid%3D1&ved=abc"
+        ),
+        Some("https://example.com/page?id=1".to_string())
+    );
+    assert_eq!(
+        provider.get_redirection(
+            "https://www.google.com/url?q=https%3A%2F%2Fexample.com%2Fs%3Fq%3Da%2526b%26x%3D100%2525&ved=abc"
+        ),
+        Some("https://example.com/s?q=a%26b&x=100%25".to_string())
+    );
+    assert_eq!(
+        provider.get_redirection("https://www.google.com/search?q=foo"),
+        None
+    );
+}
+
+#[test]
+fn test_bundled_rules() {
+    assert!(verify_rules_hash(BUNDLED_RULES.as_bytes(), BUNDLED_RULES_HASH).is_ok());
+
+    let providers = bundled_providers();
+
+    assert!(providers.providers.contains_key("globalRules"));
+    assert!(providers
+        .providers
+        .values()
+        .all(|details| CompiledProviderDetails::new(details).is_ok()));
+
+    let local_providers = serde_json::from_str::<Providers>(include_str!("../rules/local.json"))
+        .expect("Unable to parse local rules.");
+    assert!(local_providers
         .providers
+        .values()
+        .all(|details| CompiledProviderDetails::new(details).is_ok()));
+}
+
+#[test]
+fn test_verify_rules_hash() {
+    let hash = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    assert!(verify_rules_hash(b"abc", hash).is_ok());
+    assert!(verify_rules_hash(b"abc", format!("{}\n", hash.to_uppercase()).as_str()).is_ok());
+    assert!(matches!(
+        verify_rules_hash(b"abd", hash),
+        Err(Error::RulesHashMismatch { .. })
+    ));
+}
+
+#[test]
+fn test_merge_providers() {
+    let parse = |rules: &str| {
+        serde_json::from_str::<Providers>(rules).expect("Unable to parse test providers.")
+    };
+    let mut providers = parse(
+        r#"{"providers": {
+            "youtube": {"urlPattern": "youtube", "completeProvider": false, "rules": ["feature"], "referralMarketing": [], "rawRules": [], "exceptions": [], "redirections": [], "forceRedirection": false},
+            "amazon": {"urlPattern": "amazon", "completeProvider": false, "rules": ["qid"], "referralMarketing": [], "rawRules": [], "exceptions": [], "redirections": [], "forceRedirection": false}
+        }}"#,
+    );
+    let local_providers = parse(
+        r#"{"providers": {
+            "youtube": {"urlPattern": "youtube", "completeProvider": false, "rules": ["feature", "si"], "referralMarketing": [], "rawRules": [], "exceptions": [], "redirections": [], "forceRedirection": false},
+            "pr0gramm shop": {"urlPattern": "pr0shop", "completeProvider": false, "rules": ["ref"], "referralMarketing": [], "rawRules": [], "exceptions": [], "redirections": [], "forceRedirection": false}
+        }}"#,
+    );
+
+    merge_providers(&mut providers, local_providers);
+
+    assert_eq!(providers.providers.len(), 3);
+    assert_eq!(providers.providers["youtube"].rules, vec!["feature", "si"]);
+    assert_eq!(providers.providers["amazon"].rules, vec!["qid"]);
+    assert!(providers.providers.contains_key("pr0gramm shop"));
+}
+
+#[test]
+fn test_compile_errors() {
+    let providers = serde_json::from_str::<Providers>(
+        r#"{"providers": {
+            "working": {"urlPattern": "example", "completeProvider": false, "rules": ["ref"], "referralMarketing": [], "rawRules": [], "exceptions": [], "redirections": [], "forceRedirection": false},
+            "broken": {"urlPattern": "example", "completeProvider": false, "rules": ["(?<=foo)bar"], "referralMarketing": [], "rawRules": [], "exceptions": [], "redirections": [], "forceRedirection": false}
+        }}"#,
+    )
+    .expect("Unable to parse test providers.");
+
+    let error = CompiledProviderDetails::new(&providers.providers["broken"])
+        .expect_err("Broken provider was compiled.");
+    assert_eq!(error.pattern, "(?<=foo)bar");
+
+    assert_eq!(compile(providers).len(), 1);
+}
+
+#[test]
+#[ignore = "Benchmark, run it with `cargo test --release bench_provider_matching -- --ignored --nocapture`"]
+fn bench_provider_matching() {
+    let providers = compile(bundled_providers());
+    let sample_urls = [
+        "https://www.amazon.de/dp/B07PGL2ZSL/ref=sr_1_1?keywords=foo&qid=123&tag=bar-21",
+        "https://www.google.com/url?sa=t&url=https%3A%2F%2Fexample.com%2F&ved=abc",
+        "https://www.youtube.com/watch?v=dQw4w9WgXcQ&si=abc",
+        "https://www.spiegel.de/wirtschaft/artikel-123.html",
+        "https://pr0gramm.com/top/123456",
+        "https://de.wikipedia.org/wiki/Rust_(Programmiersprache)",
+    ];
+    let urls: Vec<&str> = sample_urls.iter().cycle().take(60_000).copied().collect();
+
+    let scan_start = std::time::Instant::now();
+    let scan_matches: usize = urls
         .iter()
-        .map(|provider| CompiledProviderDetails::new(provider.1))
-        .filter_map(|provider| match provider {
-            Ok(compiled_provider) => Some(compiled_provider),
-            Err(_) => None,
+        .map(|url| {
+            providers
+                .providers
+                .iter()
+                .filter(|details| details.url_pattern.is_match(url))
+                .count()
         })
-        .collect();
+        .sum();
+    let scan_duration = scan_start.elapsed();
 
-    compiled_providers
+    let set_start = std::time::Instant::now();
+    let set_matches: usize = urls.iter().map(|url| providers.matching(url).len()).sum();
+    let set_duration = set_start.elapsed();
+
+    println!(
+        "{} urls against {} providers. Scanning every pattern: {:?}, pattern set: {:?}",
+        urls.len(),
+        providers.len(),
+        scan_duration,
+        set_duration
+    );
+    assert_eq!(scan_matches, set_matches);
 }
@@ -0,0 +1,378 @@
+use std::collections::{HashSet, LinkedList};
+use std::time::Duration;
+
+use http::header::{CONTENT_TYPE, LOCATION, USER_AGENT};
+use reqwest::{redirect, Client, Response};
+use url::Url;
+
+use crate::amp::is_trusted_canonical;
+use crate::config::get_config;
+use crate::error::Error;
+use crate::guard::OutboundPolicy;
+use crate::html::{find_canonical, find_meta_refresh, find_script_redirect};
+use crate::utils_api::{RedirectorResponse, ResponseType};
+
+const MAX_BODY_PREFIX: usize = 64 * 1024;
+
+static RESOLVER: once_cell::sync::OnceCell<RedirectResolver> = once_cell::sync::OnceCell::new();
+
+/// Follows redirects hop by hop, instead of letting reqwest do it, so every hop can be recorded and checked.
+pub struct RedirectResolver {
+    user_agent: String,
+    max_hops: usize,
+    timeout: Duration,
+    policy: OutboundPolicy,
+}
+
+pub fn get_resolver() -> &'static RedirectResolver {
+    RESOLVER.get_or_init(|| {
+        let config = get_config();
+        RedirectResolver::new(
+            config.redirect_max_hops,
+            config.redirect_timeout,
+            OutboundPolicy::public_only(),
+        )
+    })
+}
+
+/// Checks if the url belongs to a known url shortener or wrapper.
+/// Other urls are never requested, they only get cleaned offline.
+pub fn is_shortener(url: &Url) -> bool {
+    url.host_str()
+        .is_some_and(|host| is_shortener_domain(host, &get_config().shortener_domains))
+}
+
+fn is_shortener_domain(host: &str, shortener_domains: &[String]) -> bool {
+    let host = host.trim_end_matches('.').to_lowercase();
+
+    shortener_domains.iter().any(|domain| {
+        host == *domain
+            || host
+                .strip_suffix(domain.as_str())
+                .is_some_and(|subdomain| subdomain.ends_with('.'))
+    })
+}
+
+impl RedirectResolver {
+    pub fn new(max_hops: usize, timeout: Duration, policy: OutboundPolicy) -> RedirectResolver {
+        RedirectResolver {
+            user_agent: "Linkers URL Cleaner Bot".to_string(),
+            max_hops,
+            timeout,
+            policy,
+        }
+    }
+
+    /// Builds a client for a single hop, which is pinned to the checked addresses of the target.
+    async fn checked_client(&self, url: &Url) -> Result<Client, Error> {
+        let target = self.policy.check(url).await?;
+
+        // A proxy would resolve the host again by itself, which bypasses the pinned addresses
+        Ok(Client::builder()
+            .no_proxy()
+            .redirect(redirect::Policy::none())
+            .timeout(self.timeout)
+            .resolve_to_addrs(target.host.as_str(), &target.addresses)
+            .build()?)
+    }
+
+    /// Downloads the start of a single HTML page, without following any redirects.
+    /// Returns `None` for error responses and other content types.
+    pub async fn fetch_page(&self, url: &Url) -> Result<Option<String>, Error> {
+        let response = self
+            .checked_client(url)
+            .await?
+            .get(url.as_str())
+            .header(USER_AGENT, &self.user_agent)
+            .send()
+            .await?;
+
+        if !response.status().is_success() || !is_html(&response) {
+            return Ok(None);
+        }
+
+        Ok(Some(read_prefix(response).await?))
+    }
+
+    /// Returns the whole redirect chain, starting with the given url.
+    /// Besides HTTP redirects, meta refreshes, script redirects and canonical links of HTML pages are followed.
+    /// `result_url` is only set if the url actually redirects somewhere else.
+    pub async fn resolve(&self, url: &str) -> Result<RedirectorResponse, Error> {
+        let Ok(mut current_url) = Url::parse(url) else {
+            return Ok(RedirectorResponse {
+                result_url: None,
+                response_status: ResponseType::UrlMalformed,
+                redirect_urls: None,
+            });
+        };
+
+        let mut visited_urls = HashSet::from([current_url.to_string()]);
+        let mut redirect_urls = LinkedList::from([current_url.to_string()]);
+
+        loop {
+            let response = self
+                .checked_client(&current_url)
+                .await?
+                .get(current_url.as_str())
+                .header(USER_AGENT, &self.user_agent)
+                .send()
+                .await?;
+
+            let status = response.status();
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .map(|location| location.to_string());
+
+            let next_url = match location {
+                Some(location) if status.is_redirection() => {
+                    let next_url = current_url.join(location.as_str())?;
+                    if visited_urls.contains(next_url.as_str()) {
+                        return Err(Error::RedirectLoop(next_url.to_string()));
+                    }
+                    Some(next_url)
+                }
+                // Pages pointing to themselves, e.g. with their canonical link, end the chain
+                _ if status.is_success() && is_html(&response) => {
+                    find_document_target(&read_prefix(response).await?, &current_url)
+                        .filter(|next_url| !visited_urls.contains(next_url.as_str()))
+                }
+                _ => None,
+            };
+
+            let Some(next_url) = next_url else {
+                let response_status = if status.is_client_error() || status.is_server_error() {
+                    ResponseType::BadRequest
+                } else {
+                    ResponseType::Ok
+                };
+
+                return Ok(RedirectorResponse {
+                    result_url: (redirect_urls.len() > 1).then(|| current_url.to_string()),
+                    response_status,
+                    redirect_urls: Some(redirect_urls),
+                });
+            };
+
+            if redirect_urls.len() > self.max_hops {
+                return Err(Error::TooManyRedirects(self.max_hops));
+            }
+
+            visited_urls.insert(next_url.to_string());
+            redirect_urls.push_back(next_url.to_string());
+            current_url = next_url;
+        }
+    }
+}
+
+fn is_html(response: &Response) -> bool {
+    match response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+    {
+        Some(content_type) => content_type.contains("html"),
+        None => true,
+    }
+}
+
+/// Reads the start of the body. Redirect targets are in the head of a page, so there's no need to download all of it.
+async fn read_prefix(mut response: Response) -> Result<String, Error> {
+    let mut body = Vec::new();
+
+    while body.len() < MAX_BODY_PREFIX {
+        let Some(chunk) = response.chunk().await? else {
+            break;
+        };
+        body.extend_from_slice(&chunk);
+    }
+
+    body.truncate(MAX_BODY_PREFIX);
+    Ok(String::from_utf8_lossy(&body).to_string())
+}
+
+fn find_document_target(html: &str, url: &Url) -> Option<Url> {
+    if let Some(target) = find_meta_refresh(html).or_else(|| find_script_redirect(html)) {
+        return url.join(target.as_str()).ok();
+    }
+
+    // Canonical links are only followed if they point to the same page, many sites link their home page or another domain
+    find_canonical(html)
+        .and_then(|canonical_url| url.join(canonical_url.as_str()).ok())
+        .filter(|canonical_url| is_trusted_canonical(url, canonical_url))
+}
+
+#[cfg(test)]
+use crate::test_server::{TestResponse, TestServer};
+
+/// Resolver for tests, which may talk to the local test server.
+#[cfg(test)]
+pub fn test_resolver(max_hops: usize) -> RedirectResolver {
+    RedirectResolver::new(
+        max_hops,
+        Duration::from_secs(5),
+        OutboundPolicy {
+            allow_private_addresses: true,
+            allowed_ports: None,
+        },
+    )
+}
+
+#[tokio::test]
+async fn test_resolve_redirects() {
+    let server = TestServer::start(std::collections::HashMap::from([
+        (
+            "/short".to_string(),
+            TestResponse::redirect(301, "/middle?a=1"),
+        ),
+        (
+            "/middle?a=1".to_string(),
+            TestResponse::redirect(302, "/target"),
+        ),
+        ("/target".to_string(), TestResponse::ok("Hello")),
+        (
+            "/loop-a".to_string(),
+            TestResponse::redirect(307, "/loop-b"),
+        ),
+        (
+            "/loop-b".to_string(),
+            TestResponse::redirect(307, "/loop-a"),
+        ),
+        (
+            "/deep-1".to_string(),
+            TestResponse::redirect(302, "/deep-2"),
+        ),
+        (
+            "/deep-2".to_string(),
+            TestResponse::redirect(302, "/deep-3"),
+        ),
+        (
+            "/deep-3".to_string(),
+            TestResponse::redirect(302, "/deep-4"),
+        ),
+        ("/deep-4".to_string(), TestResponse::ok("Too deep")),
+    ]))
+    .await;
+    let resolver = test_resolver(2);
+
+    let redirects = resolver
+        .resolve(server.url("/short").as_str())
+        .await
+        .expect("Unable to resolve redirects.");
+    assert_eq!(redirects.result_url, Some(server.url("/target")));
+    assert_eq!(
+        redirects.redirect_urls,
+        Some(LinkedList::from([
+            server.url("/short"),
+            server.url("/middle?a=1"),
+            server.url("/target"),
+        ]))
+    );
+
+    let no_redirects = resolver
+        .resolve(server.url("/target").as_str())
+        .await
+        .expect("Unable to resolve redirects.");
+    assert_eq!(no_redirects.result_url, None);
+
+    assert!(matches!(
+        resolver.resolve(server.url("/loop-a").as_str()).await,
+        Err(Error::RedirectLoop(_))
+    ));
+    assert!(matches!(
+        resolver.resolve(server.url("/deep-1").as_str()).await,
+        Err(Error::TooManyRedirects(2))
+    ));
+    let requests = server.requests();
+    assert!(requests
+        .iter()
+        .all(|request| request.method == "GET" && request.body.is_empty()));
+    assert!(!requests.iter().any(|request| request.path == "/deep-4"));
+
+    let public_resolver =
+        RedirectResolver::new(2, Duration::from_secs(5), OutboundPolicy::public_only());
+    assert!(matches!(
+        public_resolver.resolve(server.url("/short").as_str()).await,
+        Err(Error::BlockedRequest { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_resolve_document_redirects() {
+    let server = TestServer::start(std::collections::HashMap::from([
+        (
+            "/refresh".to_string(),
+            TestResponse::ok(
+                r#"<html><head><meta http-equiv="refresh" content="0; url=/script"></head></html>"#,
+            ),
+        ),
+        (
+            "/script".to_string(),
+            TestResponse::ok(
+                r#"<html><head><script>window.location.replace("/article/?a=1&amp;b=2");</script></head>"#,
+            ),
+        ),
+        (
+            "/article/?a=1&b=2".to_string(),
+            TestResponse::ok(r#"<link rel="canonical" href="/article">"#),
+        ),
+        (
+            "/article".to_string(),
+            TestResponse::ok(r#"<link rel="canonical" href="/article">"#),
+        ),
+        (
+            "/app/product-123".to_string(),
+            TestResponse::ok(r#"<link rel="canonical" href="/">"#),
+        ),
+        (
+            "/news/article-123".to_string(),
+            TestResponse::ok(
+                r#"<link rel="canonical" href="https://other.example.org/news/article-123">"#,
+            ),
+        ),
+    ]))
+    .await;
+    let resolver = test_resolver(5);
+
+    let redirects = resolver
+        .resolve(server.url("/refresh").as_str())
+        .await
+        .expect("Unable to resolve redirects.");
+    assert_eq!(redirects.result_url, Some(server.url("/article")));
+    assert_eq!(
+        redirects.redirect_urls,
+        Some(LinkedList::from([
+            server.url("/refresh"),
+            server.url("/script"),
+            server.url("/article/?a=1&b=2"),
+            server.url("/article"),
+        ]))
+    );
+
+    let self_canonical = resolver
+        .resolve(server.url("/article").as_str())
+        .await
+        .expect("Unable to resolve redirects.");
+    assert_eq!(self_canonical.result_url, None);
+
+    for untrusted_canonical in ["/app/product-123", "/news/article-123"] {
+        let redirects = resolver
+            .resolve(server.url(untrusted_canonical).as_str())
+            .await
+            .expect("Unable to resolve redirects.");
+        assert_eq!(redirects.result_url, None, "Url: {}", untrusted_canonical);
+    }
+}
+
+#[test]
+fn test_is_shortener_domain() {
+    let shortener_domains = vec!["bit.ly".to_string(), "t.co".to_string()];
+
+    assert!(is_shortener_domain("bit.ly", &shortener_domains));
+    assert!(is_shortener_domain("BIT.LY", &shortener_domains));
+    assert!(is_shortener_domain("j.bit.ly", &shortener_domains));
+    assert!(!is_shortener_domain("rabbit.ly", &shortener_domains));
+    assert!(!is_shortener_domain("t.com", &shortener_domains));
+    assert!(!is_shortener_domain("example.com", &shortener_domains));
+}
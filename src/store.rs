@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::fs;
+use std::hash::Hash;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// Map whose entries expire after a while. Expired entries are ignored and dropped on the next cleanup.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TtlMap<K: Eq + Hash, V> {
+    entries: HashMap<K, TtlEntry<V>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct TtlEntry<V> {
+    value: V,
+    /// Unix timestamp in seconds
+    expires_at: i64,
+}
+
+impl<K: Eq + Hash, V> Default for TtlMap<K, V> {
+    fn default() -> Self {
+        TtlMap {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash, V> TtlMap<K, V> {
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries
+            .get(key)
+            .filter(|entry| entry.expires_at > now())
+            .map(|entry| &entry.value)
+    }
+
+    /// All entries which didn't expire yet.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        let now = now();
+        self.entries
+            .iter()
+            .filter(move |(_, entry)| entry.expires_at > now)
+            .map(|(key, entry)| (key, &entry.value))
+    }
+
+    pub fn insert(&mut self, key: K, value: V, ttl: Duration) {
+        let expires_at = now().saturating_add(i64::try_from(ttl.as_secs()).unwrap_or(i64::MAX));
+        self.entries.insert(key, TtlEntry { value, expires_at });
+    }
+
+    pub fn remove_expired(&mut self) {
+        let now = now();
+        self.entries.retain(|_, entry| entry.expires_at > now);
+    }
+}
+
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+/// Loads json data from a file. Returns the default value if the file doesn't exist or can't be read.
+pub fn load_json<T: DeserializeOwned + Default>(path: &str) -> T {
+    let Ok(content) = fs::read(path) else {
+        return T::default();
+    };
+
+    serde_json::from_slice::<T>(&content).unwrap_or_else(|error| {
+        println!("Unable to read {}, starting empty. Error: {}", path, error);
+        T::default()
+    })
+}
+
+/// Writes json data to a file. A temporary file gets renamed over the old one,
+/// so a crash while writing doesn't leave a broken file behind.
+pub fn save_json<T: Serialize>(path: &str, value: &T) -> Result<(), Error> {
+    let temporary_path = format!("{}.tmp", path);
+
+    if let Some(parent) = Path::new(path).parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+
+    fs::write(temporary_path.as_str(), serde_json::to_vec(value)?)?;
+    fs::rename(temporary_path.as_str(), path)?;
+
+    Ok(())
+}
+
+/// Path of a file in a temporary directory for tests. An old file from a previous run is removed.
+#[cfg(test)]
+pub fn test_file_path(name: &str) -> String {
+    let path = std::env::temp_dir()
+        .join(format!("linkers-test-{}", std::process::id()))
+        .join(name);
+    let _ = fs::remove_file(&path);
+
+    path.to_str()
+        .expect("Temp dir isn't valid utf-8.")
+        .to_string()
+}
+
+#[test]
+fn test_ttl_map() {
+    let mut map = TtlMap::default();
+    map.insert("fresh".to_string(), 1, Duration::from_secs(60));
+    map.insert("expired".to_string(), 2, Duration::ZERO);
+
+    assert_eq!(map.get(&"fresh".to_string()), Some(&1));
+    assert_eq!(map.get(&"expired".to_string()), None);
+
+    map.remove_expired();
+    assert_eq!(map.entries.len(), 1);
+
+    let path = test_file_path("ttl_map.json");
+
+    save_json(path.as_str(), &map).expect("Unable to save map.");
+    let loaded_map = load_json::<TtlMap<String, i32>>(path.as_str());
+    assert_eq!(loaded_map.get(&"fresh".to_string()), Some(&1));
+
+    let _ = fs::remove_file(path);
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Minimal HTTP server for tests. It answers with scripted responses per path and records every request it gets.
+pub struct TestServer {
+    pub address: SocketAddr,
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// Path including the query
+    pub path: String,
+    pub body: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct TestResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl TestResponse {
+    pub fn ok(body: &str) -> TestResponse {
+        TestResponse {
+            status: 200,
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    pub fn redirect(status: u16, location: &str) -> TestResponse {
+        TestResponse {
+            status,
+            headers: vec![("Location".to_string(), location.to_string())],
+            body: String::new(),
+        }
+    }
+
+    pub fn status(status: u16, body: &str) -> TestResponse {
+        TestResponse {
+            status,
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+}
+
+impl TestServer {
+    /// Starts the server on a random local port. Paths without a route get a 404.
+    pub async fn start(routes: HashMap<String, TestResponse>) -> TestServer {
+        TestServer::start_scripted(
+            routes
+                .into_iter()
+                .map(|(path, response)| (path, vec![response]))
+                .collect(),
+        )
+        .await
+    }
+
+    /// Like `start`, but every request to a path gets the next of its responses. The last one is repeated.
+    pub async fn start_scripted(routes: HashMap<String, Vec<TestResponse>>) -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Unable to bind test server.");
+        let address = listener
+            .local_addr()
+            .expect("Unable to get test server address.");
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let routes = Arc::new(routes);
+
+        let server_requests = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let routes = routes.clone();
+                let requests = server_requests.clone();
+                tokio::spawn(async move { handle_connection(stream, &routes, &requests).await });
+            }
+        });
+
+        TestServer { address, requests }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.address, path)
+    }
+
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    routes: &HashMap<String, Vec<TestResponse>>,
+    requests: &Mutex<Vec<RecordedRequest>>,
+) {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    let header_end = loop {
+        let Ok(read) = stream.read(&mut chunk).await else {
+            return;
+        };
+        if read == 0 {
+            return;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+
+    while buffer.len() < header_end + content_length {
+        match stream.read(&mut chunk).await {
+            Ok(read) if read > 0 => buffer.extend_from_slice(&chunk[..read]),
+            _ => break,
+        }
+    }
+
+    let body = String::from_utf8_lossy(&buffer[header_end..]).to_string();
+    let earlier_requests = {
+        let mut requests = requests.lock().unwrap_or_else(PoisonError::into_inner);
+        let earlier_requests = requests
+            .iter()
+            .filter(|request| request.path == path)
+            .count();
+        requests.push(RecordedRequest {
+            method,
+            path: path.clone(),
+            body,
+        });
+        earlier_requests
+    };
+
+    let response = routes
+        .get(path.as_str())
+        .and_then(|responses| responses.get(earlier_requests).or_else(|| responses.last()))
+        .cloned()
+        .unwrap_or_else(|| TestResponse::status(404, "Not Found"));
+
+    let mut raw_response = format!(
+        "HTTP/1.1 {} Test\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        raw_response += format!("{}: {}\r\n", name, value).as_str();
+    }
+    raw_response += "\r\n";
+    raw_response += response.body.as_str();
+
+    let _ = stream.write_all(raw_response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}